@@ -4,8 +4,6 @@ use logging_allocator::{run_guarded, LoggingAllocator};
 static ALLOC: LoggingAllocator = LoggingAllocator::new(true);
 
 fn main() {
-    simple_logger::init().unwrap();
-
     let mut vec = vec![0; 4];
     run_guarded(|| eprintln!("Inserting some numbers"));
     vec.extend(&[1, 2, 3, 4, 5]);
@@ -0,0 +1,482 @@
+use std::alloc::{self, Layout};
+use std::cell::{Cell, UnsafeCell};
+use std::mem::MaybeUninit;
+use std::ptr;
+use std::sync::atomic::{AtomicBool, AtomicPtr, AtomicU64, AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::thread;
+use std::time::Duration;
+
+use crate::event::thread_id;
+use crate::exit::{self, ExitHook};
+use crate::{local, AllocEvent, Sink};
+
+/// The maximum number of threads that can log through a `BackgroundSink` at once. Events from
+/// further threads are dropped.
+pub const MAX_THREADS: usize = 256;
+
+/// The number of events each thread can buffer before further events are dropped.
+pub const BUFFER_CAPACITY: usize = 1024;
+
+const DRAIN_INTERVAL: Duration = Duration::from_millis(10);
+
+/// The number of sinks each thread keeps its ring for at once. Logging to a further sink releases
+/// the ring of the least recently claimed one.
+const CACHED_RINGS: usize = 4;
+
+/// The owner of a ring whose sink was dropped while a thread still held it. The thread frees the
+/// ring when it releases it.
+const ORPHANED: u64 = u64::MAX;
+
+thread_local! {
+    static HANDLE: RingHandle = const {
+        RingHandle {
+            rings: [const { Cell::new((0, ptr::null())) }; CACHED_RINGS],
+            next: Cell::new(0),
+        }
+    };
+}
+
+/// A sink that queues events in per-thread lock-free ring buffers and forwards them to an inner
+/// sink from a dedicated drain thread.
+///
+/// Events are buffered until [`start`](BackgroundSink::start) is called. Each thread's buffer is
+/// allocated the first time it logs an event. Events that have not been drained when the sink is
+/// dropped are discarded.
+pub struct BackgroundSink<S> {
+    sink: S,
+    /// Identifies the sink in the ring caches of threads, or zero until the first event is logged.
+    id: AtomicU64,
+    rings: [AtomicPtr<Ring>; MAX_THREADS],
+    started: AtomicBool,
+    /// Events dropped and reported to the inner sink.
+    dropped: AtomicU64,
+    /// Events dropped because no ring was available, not yet reported to the inner sink.
+    lost: AtomicU64,
+    drain: Mutex<()>,
+}
+
+/// A single-producer single-consumer queue of events owned by one thread.
+struct Ring {
+    owner: AtomicU64,
+    head: AtomicUsize,
+    tail: AtomicUsize,
+    dropped: AtomicU64,
+    events: [UnsafeCell<MaybeUninit<AllocEvent>>; BUFFER_CAPACITY],
+}
+
+/// The rings owned by the current thread, by the id of their sink. Releases them when the
+/// thread exits, so other threads can reuse them.
+struct RingHandle {
+    rings: [Cell<(u64, *const Ring)>; CACHED_RINGS],
+    /// The slot to evict when every slot is in use.
+    next: Cell<usize>,
+}
+
+// Slots in `events` between `head` and `tail` are only accessed by the consumer, and the rest only
+// by the producer.
+unsafe impl Sync for Ring {}
+
+impl<S> BackgroundSink<S> {
+    pub const fn new(sink: S) -> Self {
+        BackgroundSink {
+            sink,
+            id: AtomicU64::new(0),
+            rings: [const { AtomicPtr::new(ptr::null_mut()) }; MAX_THREADS],
+            started: AtomicBool::new(false),
+            dropped: AtomicU64::new(0),
+            lost: AtomicU64::new(0),
+            drain: Mutex::new(()),
+        }
+    }
+
+    pub fn inner(&self) -> &S {
+        &self.sink
+    }
+
+    /// The total number of events dropped because a buffer was full or no buffer was available,
+    /// or lost before reaching this sink.
+    pub fn dropped_events(&self) -> u64 {
+        let pending: u64 = self
+            .rings()
+            .map(|ring| ring.dropped.load(Ordering::Relaxed))
+            .sum();
+        self.dropped.load(Ordering::Relaxed) + self.lost.load(Ordering::Relaxed) + pending
+    }
+
+    fn rings(&self) -> impl Iterator<Item = &Ring> {
+        self.rings
+            .iter()
+            .filter_map(|ring| unsafe { ring.load(Ordering::Acquire).as_ref() })
+    }
+
+    /// A unique id for this sink. Unlike its address, it is never reused by a later sink.
+    fn id(&self) -> u64 {
+        static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+
+        let id = self.id.load(Ordering::Relaxed);
+        if id != 0 {
+            return id;
+        }
+        let new = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+        match self
+            .id
+            .compare_exchange(0, new, Ordering::Relaxed, Ordering::Relaxed)
+        {
+            Ok(_) => new,
+            Err(id) => id,
+        }
+    }
+
+    /// Find or claim the ring owned by the current thread.
+    fn ring(&self) -> Option<&Ring> {
+        let owner = self.id();
+        let cached = HANDLE.try_with(|handle| handle.get(owner)).ok()?;
+        if let Some(ring) = cached {
+            return Some(unsafe { &*ring });
+        }
+
+        let ring = self.claim(thread_id())?;
+        if HANDLE
+            .try_with(|handle| handle.insert(owner, ring))
+            .is_err()
+        {
+            // The thread is exiting, so the ring could not be released later.
+            ring.owner.store(0, Ordering::Release);
+            return None;
+        }
+        Some(ring)
+    }
+
+    fn claim(&self, thread: u64) -> Option<&Ring> {
+        for slot in &self.rings {
+            let mut ring = slot.load(Ordering::Acquire);
+            if ring.is_null() {
+                let new = Ring::alloc(thread)?;
+                match slot.compare_exchange(
+                    ptr::null_mut(),
+                    new,
+                    Ordering::AcqRel,
+                    Ordering::Acquire,
+                ) {
+                    Ok(_) => return Some(unsafe { &*new }),
+                    Err(existing) => {
+                        unsafe { Ring::dealloc(new) };
+                        ring = existing;
+                    }
+                }
+            }
+            let ring = unsafe { &*ring };
+            if ring
+                .owner
+                .compare_exchange(0, thread, Ordering::Acquire, Ordering::Relaxed)
+                .is_ok()
+            {
+                return Some(ring);
+            }
+        }
+        None
+    }
+}
+
+impl<S> BackgroundSink<S>
+where
+    S: Sink + Sync,
+{
+    /// Spawn the drain thread and arrange for buffered events to be flushed when the process
+    /// exits. Calling this more than once has no effect.
+    pub fn start(&'static self) {
+        if self.started.swap(true, Ordering::SeqCst) {
+            return;
+        }
+        exit::register(self);
+        thread::Builder::new()
+            .name("logging-allocator".to_owned())
+            .spawn(move || {
+                // Allocations made while writing events are the allocator's own, so they must not
+                // produce more events or be made to fail.
+                local::bookkeeping(|| loop {
+                    self.drain();
+                    thread::park_timeout(DRAIN_INTERVAL);
+                })
+            })
+            .expect("failed to spawn drain thread");
+    }
+}
+
+impl<S> BackgroundSink<S>
+where
+    S: Sink,
+{
+    /// Forward all buffered events to the inner sink.
+    pub fn drain(&self) {
+        let _lock = self.drain.lock().unwrap_or_else(|err| err.into_inner());
+        let mut dropped = self.lost.swap(0, Ordering::Relaxed);
+        for ring in self.rings() {
+            ring.drain(|event| self.sink.log(event));
+            dropped += ring.dropped.swap(0, Ordering::Relaxed);
+        }
+        if dropped != 0 {
+            self.dropped.fetch_add(dropped, Ordering::Relaxed);
+            self.sink.dropped(dropped);
+        }
+    }
+}
+
+impl<S> Sink for BackgroundSink<S>
+where
+    S: Sink,
+{
+    fn log(&self, event: &AllocEvent) {
+        match self.ring() {
+            Some(ring) => ring.push(event),
+            None => {
+                self.lost.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+
+    fn dropped(&self, count: u64) {
+        // Reported to the inner sink by the next drain, along with the events dropped here.
+        self.lost.fetch_add(count, Ordering::Relaxed);
+    }
+
+    fn flush(&self) {
+        self.drain();
+        self.sink.flush()
+    }
+}
+
+impl<S> Drop for BackgroundSink<S> {
+    fn drop(&mut self) {
+        for slot in &self.rings {
+            let ring = slot.load(Ordering::Acquire);
+            if ring.is_null() {
+                continue;
+            }
+            // A ring still held by a thread is freed by that thread when it releases it.
+            if unsafe { (*ring).owner.swap(ORPHANED, Ordering::AcqRel) } == 0 {
+                unsafe { Ring::dealloc(ring) };
+            }
+        }
+    }
+}
+
+impl<S> ExitHook for BackgroundSink<S>
+where
+    S: Sink + Sync,
+{
+    fn at_exit(&self) {
+        local::bookkeeping(|| {
+            self.drain();
+            self.sink.flush();
+        });
+    }
+}
+
+impl Ring {
+    fn alloc(owner: u64) -> Option<*mut Ring> {
+        // An all-zero ring is empty and valid.
+        let ring = unsafe { alloc::alloc_zeroed(Layout::new::<Ring>()) as *mut Ring };
+        if ring.is_null() {
+            return None;
+        }
+        unsafe { (*ring).owner.store(owner, Ordering::Relaxed) };
+        Some(ring)
+    }
+
+    unsafe fn dealloc(ring: *mut Ring) {
+        alloc::dealloc(ring as *mut u8, Layout::new::<Ring>());
+    }
+
+    fn push(&self, event: &AllocEvent) {
+        let tail = self.tail.load(Ordering::Relaxed);
+        if tail.wrapping_sub(self.head.load(Ordering::Acquire)) == BUFFER_CAPACITY {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+        unsafe { (*self.events[tail % BUFFER_CAPACITY].get()).write(*event) };
+        self.tail.store(tail.wrapping_add(1), Ordering::Release);
+    }
+
+    fn drain(&self, mut f: impl FnMut(&AllocEvent)) {
+        let mut head = self.head.load(Ordering::Relaxed);
+        let tail = self.tail.load(Ordering::Acquire);
+        while head != tail {
+            let event = unsafe { (*self.events[head % BUFFER_CAPACITY].get()).assume_init() };
+            head = head.wrapping_add(1);
+            self.head.store(head, Ordering::Release);
+            f(&event);
+        }
+    }
+}
+
+impl RingHandle {
+    fn get(&self, owner: u64) -> Option<*const Ring> {
+        self.rings
+            .iter()
+            .map(Cell::get)
+            .find(|&(sink, _)| sink == owner)
+            .map(|(_, ring)| ring)
+    }
+
+    /// Remember the ring claimed for a sink, releasing the ring in the slot it replaces.
+    fn insert(&self, owner: u64, ring: &Ring) {
+        let slot = match self.rings.iter().position(|slot| slot.get().0 == 0) {
+            Some(slot) => slot,
+            None => {
+                let slot = self.next.get();
+                self.next.set((slot + 1) % CACHED_RINGS);
+                slot
+            }
+        };
+        let (_, evicted) = self.rings[slot].replace((owner, ring));
+        release(evicted);
+    }
+}
+
+impl Drop for RingHandle {
+    fn drop(&mut self) {
+        for slot in &self.rings {
+            release(slot.get().1);
+        }
+    }
+}
+
+fn release(ring: *const Ring) {
+    if let Some(owner) = unsafe { ring.as_ref() }.map(|ring| &ring.owner) {
+        if owner.swap(0, Ordering::AcqRel) == ORPHANED {
+            unsafe { Ring::dealloc(ring as *mut Ring) };
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::mpsc;
+
+    use super::*;
+    use crate::{Block, EventInfo};
+
+    #[derive(Default)]
+    struct Count {
+        logged: AtomicU64,
+        dropped: AtomicU64,
+    }
+
+    impl Sink for Count {
+        fn log(&self, _: &AllocEvent) {
+            self.logged.fetch_add(1, Ordering::Relaxed);
+        }
+
+        fn dropped(&self, count: u64) {
+            self.dropped.fetch_add(count, Ordering::Relaxed);
+        }
+    }
+
+    fn event() -> AllocEvent {
+        AllocEvent::Alloc {
+            block: Block::new(ptr::null_mut(), 8, 8),
+            info: EventInfo::default(),
+        }
+    }
+
+    fn owned(sink: &BackgroundSink<Count>) -> usize {
+        sink.rings()
+            .filter(|ring| ring.owner.load(Ordering::Relaxed) != 0)
+            .count()
+    }
+
+    #[test]
+    fn alternating_sinks() {
+        let sinks = [
+            BackgroundSink::new(Count::default()),
+            BackgroundSink::new(Count::default()),
+        ];
+        for _ in 0..300 {
+            for sink in &sinks {
+                sink.log(&event());
+            }
+        }
+        for sink in &sinks {
+            assert_eq!(owned(sink), 1);
+            sink.drain();
+            assert_eq!(sink.dropped_events(), 0);
+            assert_eq!(sink.inner().logged.load(Ordering::Relaxed), 300);
+        }
+    }
+
+    #[test]
+    fn release_on_exit() {
+        let sinks: &'static [_] = Vec::leak(
+            (0..CACHED_RINGS + 1)
+                .map(|_| BackgroundSink::new(Count::default()))
+                .collect(),
+        );
+        // Joining waits for the thread's locals to be dropped, unlike leaving a scope.
+        thread::spawn(move || {
+            for sink in sinks {
+                sink.log(&event());
+            }
+            // The ring of the first sink was released to make room for the last.
+            assert_eq!(owned(&sinks[0]), 0);
+            assert!(sinks[1..].iter().all(|sink| owned(sink) == 1));
+        })
+        .join()
+        .unwrap();
+        for sink in sinks {
+            assert_eq!(owned(sink), 0);
+            sink.drain();
+            assert_eq!(sink.inner().logged.load(Ordering::Relaxed), 1);
+        }
+    }
+
+    #[test]
+    fn reused_address() {
+        let mut sink = Box::new(BackgroundSink::new(Count::default()));
+        sink.log(&event());
+        // The new sink takes the place of the old one, which frees its ring.
+        *sink = BackgroundSink::new(Count::default());
+        sink.log(&event());
+        assert_eq!(owned(&sink), 1);
+        sink.drain();
+        assert_eq!(sink.inner().logged.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn drop_while_held() {
+        let sink = Box::new(BackgroundSink::new(Count::default()));
+        let (send_sink, recv_sink) = mpsc::channel();
+        let (send_dropped, recv_dropped) = mpsc::channel();
+        let thread = thread::spawn(move || {
+            sink.log(&event());
+            send_sink.send(sink).unwrap();
+            // The ring of the dropped sink is freed when the thread exits.
+            recv_dropped.recv().unwrap();
+        });
+        let sink = recv_sink.recv().unwrap();
+        assert_eq!(owned(&sink), 1);
+        drop(sink);
+        send_dropped.send(()).unwrap();
+        thread.join().unwrap();
+    }
+
+    #[test]
+    fn flush_drains() {
+        let sink = BackgroundSink::new(Count::default());
+        sink.log(&event());
+        Sink::flush(&sink);
+        assert_eq!(sink.inner().logged.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn report_lost() {
+        let sink = BackgroundSink::new(Count::default());
+        sink.lost.fetch_add(1, Ordering::Relaxed);
+        Sink::dropped(&sink, 2);
+        assert_eq!(sink.dropped_events(), 3);
+        sink.drain();
+        assert_eq!(sink.dropped_events(), 3);
+        assert_eq!(sink.inner().dropped.load(Ordering::Relaxed), 3);
+    }
+}
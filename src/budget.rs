@@ -0,0 +1,139 @@
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Mutex;
+
+use crate::{local, Callsite, StackId};
+
+/// The number of callsites printed when the memory limit is exceeded.
+const REPORTED_CALLSITES: usize = 10;
+
+/// What to do when an allocation would take the bytes in use over the memory limit.
+#[derive(Clone, Copy, Debug)]
+pub enum BudgetAction {
+    /// Make the allocation fail.
+    Fail,
+    /// Call the hook, then let the allocation proceed. The hook is called from inside the
+    /// allocator, and blocks it allocates are not tracked.
+    Hook(fn(&BudgetExceeded)),
+}
+
+/// An allocation that would take the bytes in use over the memory limit.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct BudgetExceeded {
+    /// The size of the block being allocated or reallocated.
+    pub size: usize,
+    /// The number of bytes in use before the call.
+    pub bytes_in_use: usize,
+    /// The number of bytes the call would add to the bytes in use.
+    pub growth: usize,
+    pub limit: usize,
+    pub stack: Option<StackId>,
+}
+
+/// A limit on the number of bytes in use, and the action taken when it would be exceeded.
+pub(crate) struct Budget {
+    limit: AtomicUsize,
+    action: Mutex<BudgetAction>,
+    exceeded: AtomicU64,
+}
+
+impl Budget {
+    pub const fn new() -> Self {
+        Budget {
+            limit: AtomicUsize::new(usize::MAX),
+            action: Mutex::new(BudgetAction::Fail),
+            exceeded: AtomicU64::new(0),
+        }
+    }
+
+    pub fn limit(&self) -> Option<usize> {
+        match self.limit.load(Ordering::Relaxed) {
+            usize::MAX => None,
+            limit => Some(limit),
+        }
+    }
+
+    pub fn set(&self, limit: usize, action: BudgetAction) {
+        let mut current = self.action.lock().unwrap_or_else(|err| err.into_inner());
+        *current = action;
+        self.limit.store(limit, Ordering::Relaxed);
+    }
+
+    pub fn clear(&self) {
+        self.limit.store(usize::MAX, Ordering::Relaxed)
+    }
+
+    /// The number of calls that would have exceeded the limit.
+    pub fn exceeded(&self) -> u64 {
+        self.exceeded.load(Ordering::Relaxed)
+    }
+
+    /// Check a call that adds `growth` bytes to the `bytes_in_use`, reporting it if it would
+    /// exceed the limit. `stack` captures the current stack and `callsites` gets the callsites
+    /// with the most live bytes; they are only called if the limit would be exceeded. Returns
+    /// whether the call should proceed.
+    pub fn check<F, G>(
+        &self,
+        size: usize,
+        bytes_in_use: usize,
+        growth: usize,
+        stack: F,
+        callsites: G,
+    ) -> bool
+    where
+        F: FnOnce() -> Option<StackId>,
+        G: FnOnce(usize) -> Vec<Callsite>,
+    {
+        let limit = self.limit.load(Ordering::Relaxed);
+        if growth == 0 || bytes_in_use.saturating_add(growth) <= limit {
+            return true;
+        }
+        // Allocations made to report a call, or by sinks, are not limited.
+        if local::with(|local| local.busy.get()) {
+            return true;
+        }
+        self.exceeded.fetch_add(1, Ordering::Relaxed);
+        let action = *self.action.lock().unwrap_or_else(|err| err.into_inner());
+        let proceed = local::bookkeeping(|| {
+            let exceeded = BudgetExceeded {
+                size,
+                bytes_in_use,
+                growth,
+                limit,
+                stack: stack(),
+            };
+            report(&exceeded, &callsites(REPORTED_CALLSITES));
+            match action {
+                BudgetAction::Fail => false,
+                BudgetAction::Hook(hook) => {
+                    hook(&exceeded);
+                    true
+                }
+            }
+        });
+        proceed.unwrap_or(true)
+    }
+}
+
+/// Print a call that would exceed the memory limit and the callsites with the most live bytes to
+/// stderr.
+fn report(exceeded: &BudgetExceeded, callsites: &[Callsite]) {
+    eprint!(
+        "allocation of {} bytes would exceed the memory limit of {} bytes with {} bytes in use",
+        exceeded.size, exceeded.limit, exceeded.bytes_in_use
+    );
+    match exceeded.stack {
+        Some(stack) => eprint!(" at:\n{}", stack),
+        None => eprintln!(),
+    }
+    if callsites.is_empty() {
+        eprintln!("no tracked callsites have live bytes");
+        return;
+    }
+    eprintln!("top live callsites:");
+    for callsite in callsites {
+        eprint!(
+            "{} bytes live from {} allocations at:\n{}",
+            callsite.live_bytes, callsite.allocations, callsite.stack
+        );
+    }
+}
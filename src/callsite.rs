@@ -0,0 +1,191 @@
+use std::alloc::GlobalAlloc;
+use std::cmp::Reverse;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+
+use crate::table::Table;
+use crate::{StackId, STACK_CAPACITY};
+
+/// Totals for all allocations made from one unique stack. When sampling is enabled these are
+/// estimates derived from the weights of the sampled allocations.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Callsite {
+    pub stack: StackId,
+    /// The number of blocks allocated or reallocated from this stack.
+    pub allocations: u64,
+    /// The total size of all blocks allocated or reallocated from this stack.
+    pub bytes: u64,
+    /// The total size of blocks allocated from this stack that have not been freed.
+    pub live_bytes: usize,
+}
+
+/// The order in which to report callsites.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CallsiteOrder {
+    /// Largest total bytes allocated first.
+    Bytes,
+    /// Largest number of allocations first.
+    Count,
+    /// Largest number of bytes still live first.
+    LiveBytes,
+}
+
+/// Allocation counts are kept in fixed point with this many units per allocation, so that
+/// fractional sampling weights are not lost.
+const COUNT_SCALE: f64 = 1024.0;
+
+/// Per-stack allocation counters, indexed by `StackId`.
+pub(crate) struct Callsites {
+    counters: Table<Counters>,
+}
+
+#[derive(Default)]
+struct Counters {
+    allocations: AtomicU64,
+    bytes: AtomicU64,
+    live_bytes: AtomicUsize,
+}
+
+impl Callsites {
+    pub const fn new() -> Self {
+        Callsites {
+            counters: Table::new(STACK_CAPACITY),
+        }
+    }
+
+    pub fn init<A>(&self, allocator: &A)
+    where
+        A: GlobalAlloc,
+    {
+        // All-zero counters are valid.
+        unsafe { self.counters.get_or_init(allocator) };
+    }
+
+    fn get(&self, stack: StackId) -> Option<&Counters> {
+        self.counters.get()?.get(stack.as_raw() as usize)
+    }
+
+    pub fn alloc(&self, stack: StackId, size: usize, weight: f64) {
+        if let Some(counters) = self.get(stack) {
+            let bytes = weighted(size, weight);
+            counters
+                .allocations
+                .fetch_add((weight * COUNT_SCALE) as u64, Ordering::Relaxed);
+            counters.bytes.fetch_add(bytes as u64, Ordering::Relaxed);
+            counters.live_bytes.fetch_add(bytes, Ordering::Relaxed);
+        }
+    }
+
+    pub fn dealloc(&self, stack: StackId, size: usize, weight: f64) {
+        if let Some(counters) = self.get(stack) {
+            counters
+                .live_bytes
+                .fetch_sub(weighted(size, weight), Ordering::Relaxed);
+        }
+    }
+
+    pub fn top(&self, n: usize, order: CallsiteOrder) -> Vec<Callsite> {
+        let counters = self.counters.get().unwrap_or(&[]);
+        let mut callsites: Vec<Callsite> = counters
+            .iter()
+            .enumerate()
+            .filter_map(|(index, counters)| {
+                let allocations = counters.allocations.load(Ordering::Relaxed);
+                if allocations == 0 {
+                    return None;
+                }
+                let allocations = (allocations as f64 / COUNT_SCALE).round() as u64;
+                Some(Callsite {
+                    stack: StackId::from_raw(index as u32),
+                    allocations,
+                    bytes: counters.bytes.load(Ordering::Relaxed),
+                    live_bytes: counters.live_bytes.load(Ordering::Relaxed),
+                })
+            })
+            .collect();
+        match order {
+            CallsiteOrder::Bytes => callsites.sort_by_key(|callsite| Reverse(callsite.bytes)),
+            CallsiteOrder::Count => callsites.sort_by_key(|callsite| Reverse(callsite.allocations)),
+            CallsiteOrder::LiveBytes => {
+                callsites.sort_by_key(|callsite| Reverse(callsite.live_bytes))
+            }
+        }
+        callsites.truncate(n);
+        callsites
+    }
+}
+
+fn weighted(size: usize, weight: f64) -> usize {
+    (size as f64 * weight) as usize
+}
+
+#[cfg(test)]
+mod tests {
+    use std::alloc::System;
+
+    use super::*;
+
+    fn callsites() -> Callsites {
+        let callsites = Callsites::new();
+        callsites.init(&System);
+        // Many small blocks, a few large ones, and one large block that is still live.
+        for _ in 0..10 {
+            callsites.alloc(StackId::from_raw(1), 8, 1.0);
+            callsites.dealloc(StackId::from_raw(1), 8, 1.0);
+        }
+        for _ in 0..2 {
+            callsites.alloc(StackId::from_raw(2), 1000, 1.0);
+            callsites.dealloc(StackId::from_raw(2), 1000, 1.0);
+        }
+        callsites.alloc(StackId::from_raw(3), 500, 1.0);
+        callsites
+    }
+
+    fn order(callsites: &Callsites, order: CallsiteOrder) -> Vec<u32> {
+        let top = callsites.top(usize::MAX, order);
+        top.iter().map(|callsite| callsite.stack.as_raw()).collect()
+    }
+
+    #[test]
+    fn top_order() {
+        let callsites = callsites();
+        assert_eq!(order(&callsites, CallsiteOrder::Bytes), [2, 3, 1]);
+        assert_eq!(order(&callsites, CallsiteOrder::Count), [1, 2, 3]);
+        assert_eq!(order(&callsites, CallsiteOrder::LiveBytes)[0], 3);
+        assert_eq!(callsites.top(2, CallsiteOrder::Bytes).len(), 2);
+    }
+
+    #[test]
+    fn live_bytes() {
+        let callsites = callsites();
+        let top = callsites.top(usize::MAX, CallsiteOrder::Bytes);
+        let live: Vec<_> = top.iter().map(|callsite| callsite.live_bytes).collect();
+        assert_eq!(live, [0, 500, 0]);
+
+        callsites.dealloc(StackId::from_raw(3), 500, 1.0);
+        let top = callsites.top(usize::MAX, CallsiteOrder::LiveBytes);
+        assert!(top.iter().all(|callsite| callsite.live_bytes == 0));
+        let freed = top.iter().find(|callsite| callsite.stack.as_raw() == 3);
+        assert_eq!(freed.unwrap().bytes, 500);
+    }
+
+    #[test]
+    fn weighted_totals() {
+        let callsites = Callsites::new();
+        callsites.init(&System);
+        callsites.alloc(StackId::from_raw(1), 100, 2.5);
+        callsites.alloc(StackId::from_raw(1), 100, 2.5);
+        callsites.dealloc(StackId::from_raw(1), 100, 2.5);
+        let top = callsites.top(1, CallsiteOrder::Bytes);
+        assert_eq!(top[0].allocations, 5);
+        assert_eq!(top[0].bytes, 500);
+        assert_eq!(top[0].live_bytes, 250);
+    }
+
+    #[test]
+    fn untouched_callsites_are_not_reported() {
+        let callsites = Callsites::new();
+        assert!(callsites.top(10, CallsiteOrder::Bytes).is_empty());
+        callsites.init(&System);
+        assert!(callsites.top(10, CallsiteOrder::Bytes).is_empty());
+    }
+}
@@ -0,0 +1,80 @@
+use std::sync::atomic::{AtomicU64, AtomicU8, Ordering};
+
+use crate::AllocEvent;
+
+/// Which logged events carry a backtrace.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BacktracePolicy {
+    /// Never capture a backtrace for logging.
+    Never,
+    /// Capture a backtrace for every logged event.
+    Always,
+    /// Capture a backtrace for allocations and reallocations, but not deallocations.
+    AllocOnly,
+    /// Capture a backtrace for events on blocks of at least this many bytes.
+    AtLeast(usize),
+    /// Capture a backtrace for one in every `n` logged events.
+    Sampled(u64),
+}
+
+const NEVER: u8 = 0;
+const ALWAYS: u8 = 1;
+const ALLOC_ONLY: u8 = 2;
+const AT_LEAST: u8 = 3;
+const SAMPLED: u8 = 4;
+
+/// The backtrace policy, and the number of events it has seen for sampling.
+pub(crate) struct Backtraces {
+    kind: AtomicU8,
+    /// The parameter of the policy: the size, or `n`.
+    param: AtomicU64,
+    count: AtomicU64,
+}
+
+impl Backtraces {
+    pub const fn new() -> Self {
+        Backtraces {
+            kind: AtomicU8::new(ALWAYS),
+            param: AtomicU64::new(0),
+            count: AtomicU64::new(0),
+        }
+    }
+
+    pub fn policy(&self) -> BacktracePolicy {
+        let param = self.param.load(Ordering::Relaxed);
+        match self.kind.load(Ordering::Acquire) {
+            NEVER => BacktracePolicy::Never,
+            ALLOC_ONLY => BacktracePolicy::AllocOnly,
+            AT_LEAST => BacktracePolicy::AtLeast(param as usize),
+            SAMPLED => BacktracePolicy::Sampled(param),
+            _ => BacktracePolicy::Always,
+        }
+    }
+
+    pub fn set_policy(&self, policy: BacktracePolicy) {
+        let (kind, param) = match policy {
+            BacktracePolicy::Never => (NEVER, 0),
+            BacktracePolicy::Always => (ALWAYS, 0),
+            BacktracePolicy::AllocOnly => (ALLOC_ONLY, 0),
+            BacktracePolicy::AtLeast(size) => (AT_LEAST, size as u64),
+            BacktracePolicy::Sampled(n) => (SAMPLED, n),
+        };
+        self.count.store(0, Ordering::Relaxed);
+        self.param.store(param, Ordering::Relaxed);
+        self.kind.store(kind, Ordering::Release);
+    }
+
+    /// Decide whether a logged event should carry a backtrace.
+    pub fn capture(&self, event: &AllocEvent) -> bool {
+        match self.kind.load(Ordering::Acquire) {
+            NEVER => false,
+            ALLOC_ONLY => !matches!(event, AllocEvent::Dealloc { .. }),
+            AT_LEAST => event.block().size as u64 >= self.param.load(Ordering::Relaxed),
+            SAMPLED => {
+                let n = self.param.load(Ordering::Relaxed);
+                n != 0 && self.count.fetch_add(1, Ordering::Relaxed).is_multiple_of(n)
+            }
+            _ => true,
+        }
+    }
+}
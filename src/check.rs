@@ -0,0 +1,123 @@
+use crate::live::Record;
+use crate::local;
+use crate::quarantine::UseAfterFree;
+use crate::red_zone::Corruption;
+use crate::stack::LazyStack;
+use crate::Block;
+
+/// Print a free or reallocation of `block`, which is not live, to stderr. `record` is the
+/// tombstone left when the block was last freed, if it is known.
+pub(crate) fn report_bad_free(block: Block, record: Option<Record>, stack: &mut LazyStack) {
+    let kind = match record {
+        Some(_) => "double free of",
+        None => "free of unknown block",
+    };
+    let printed = local::bookkeeping(|| {
+        match stack.get() {
+            Some(stack) => eprint!("{} {:#x} at:\n{}", kind, block.address, stack),
+            None => eprintln!("{} {:#x}", kind, block.address),
+        }
+        let record = match record {
+            Some(record) => record,
+            None => return,
+        };
+        match record.stack {
+            Some(stack) => eprint!("allocated with size {} at:\n{}", record.size, stack),
+            None => eprintln!("allocated with size {}", record.size),
+        }
+        if let Some(stack) = record.freed {
+            eprint!("freed at:\n{}", stack);
+        }
+    });
+    if printed.is_none() {
+        eprintln!("{} {:#x}", kind, block.address);
+    }
+}
+
+/// Print a free or reallocation of `block` with a different layout than it was allocated with to
+/// stderr.
+pub(crate) fn report_layout_mismatch(block: Block, record: Record, stack: &mut LazyStack) {
+    let printed = local::bookkeeping(|| {
+        eprint!(
+            "layout mismatch freeing {:#x} with size {} and align {}",
+            block.address, block.size, block.align
+        );
+        match stack.get() {
+            Some(stack) => eprint!(" at:\n{}", stack),
+            None => eprintln!(),
+        }
+        eprint!(
+            "allocated with size {} and align {}",
+            record.size, record.align
+        );
+        match record.stack {
+            Some(stack) => eprint!(" at:\n{}", stack),
+            None => eprintln!(),
+        }
+    });
+    if printed.is_none() {
+        eprintln!("layout mismatch freeing {:#x}", block.address);
+    }
+}
+
+/// Print damage found in the red zones of the block at `address` to stderr. `stack` is the stack
+/// that found it, if the block is being freed or reallocated.
+pub(crate) fn report_corruption(
+    address: usize,
+    record: Record,
+    corruption: Corruption,
+    stack: Option<&mut LazyStack>,
+) {
+    let printed = local::bookkeeping(|| {
+        if corruption.underflow > 0 {
+            eprintln!(
+                "buffer underflow: {} bytes before the block at {:#x} with size {} were overwritten",
+                corruption.underflow, address, record.size
+            );
+        }
+        if corruption.overflow > 0 {
+            eprintln!(
+                "buffer overflow: {} bytes after the block at {:#x} with size {} were overwritten",
+                corruption.overflow, address, record.size
+            );
+        }
+        if let Some(stack) = stack.and_then(LazyStack::get) {
+            eprint!("found at:\n{}", stack);
+        }
+        if let Some(stack) = record.stack {
+            eprint!("allocated at:\n{}", stack);
+        }
+    });
+    if printed.is_none() {
+        eprintln!("red zone of the block at {:#x} was overwritten", address);
+    }
+}
+
+/// Print writes found in the poison of the freed block at `address` to stderr when it leaves
+/// quarantine.
+pub(crate) fn report_use_after_free(
+    address: usize,
+    size: usize,
+    record: Option<Record>,
+    damage: UseAfterFree,
+) {
+    let printed = local::bookkeeping(|| {
+        eprintln!(
+            "use after free: {} bytes of the block at {:#x} with size {} were written after it was freed, the first at offset {}",
+            damage.bytes, address, size, damage.offset
+        );
+        let record = match record {
+            Some(record) => record,
+            None => return,
+        };
+        if let Some(stack) = record.stack {
+            eprint!("allocated at:\n{}", stack);
+        }
+        if let Some(stack) = record.freed {
+            eprint!("freed at:\n{}", stack);
+        }
+    });
+    if printed.is_none() {
+        eprintln!("use after free of the block at {:#x}", address);
+    }
+}
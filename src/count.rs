@@ -0,0 +1,213 @@
+use std::cell::Cell;
+use std::fmt;
+
+use crate::local::{self, bookkeeping};
+use crate::stack::LazyStack;
+use crate::StackId;
+
+/// The number of allocation stacks remembered by [`count_allocations`].
+pub const MAX_COUNTED_STACKS: usize = 16;
+
+/// The allocator calls made on one thread while running a closure with [`count_allocations`].
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct AllocCounts {
+    /// The number of calls to `alloc` and `alloc_zeroed`.
+    pub allocations: u64,
+    /// The number of calls to `dealloc`.
+    pub deallocations: u64,
+    /// The number of calls to `realloc`.
+    pub reallocations: u64,
+    /// The total size of blocks allocated or reallocated.
+    pub bytes: u64,
+    /// The stacks of the first allocations and reallocations, up to [`MAX_COUNTED_STACKS`].
+    pub stacks: Vec<StackId>,
+}
+
+/// Per-thread state for [`count_allocations`].
+pub(crate) struct Counter {
+    active: Cell<bool>,
+    counts: Cell<Counts>,
+    stacks: [Cell<Option<StackId>>; MAX_COUNTED_STACKS],
+}
+
+#[derive(Clone, Copy, Default)]
+struct Counts {
+    allocations: u64,
+    deallocations: u64,
+    reallocations: u64,
+    bytes: u64,
+}
+
+/// Run `f` and count the allocator calls it makes on the current thread.
+///
+/// Calls are only counted if a `LoggingAllocator` is the global allocator. Calls made by the
+/// allocator's own bookkeeping, including those made by sinks while logging, are not counted.
+/// Counting scopes nest, and calls made in an inner scope are also counted by the outer scope.
+pub fn count_allocations<F, R>(f: F) -> AllocCounts
+where
+    F: FnOnce() -> R,
+{
+    let outer = local::with(|local| local.counter.enter());
+    let restore = Restore(Some(outer));
+    drop(f());
+    let (counts, stacks) = restore.finish();
+
+    AllocCounts {
+        allocations: counts.allocations,
+        deallocations: counts.deallocations,
+        reallocations: counts.reallocations,
+        bytes: counts.bytes,
+        stacks: stacks.iter().flatten().copied().collect(),
+    }
+}
+
+type Saved = (bool, Counts, [Option<StackId>; MAX_COUNTED_STACKS]);
+
+/// Restores the enclosing counting scope, even if the closure panics.
+struct Restore(Option<Saved>);
+
+impl Restore {
+    fn finish(mut self) -> (Counts, [Option<StackId>; MAX_COUNTED_STACKS]) {
+        let outer = self.0.take().unwrap();
+        local::with(|local| local.counter.exit(outer))
+    }
+}
+
+impl Drop for Restore {
+    fn drop(&mut self) {
+        if let Some(outer) = self.0.take() {
+            local::with(|local| local.counter.exit(outer));
+        }
+    }
+}
+
+/// Assert that a block makes at most `max` allocations and reallocations on the current thread,
+/// printing the stacks of the allocations it made if it makes more.
+///
+/// ```no_run
+/// # use logging_allocator::{assert_allocations, LoggingAllocator};
+/// #[global_allocator]
+/// static ALLOC: LoggingAllocator = LoggingAllocator::new(false);
+///
+/// let numbers = [1, 2, 3];
+/// assert_allocations!(max = 0, {
+///     numbers.iter().sum::<i32>();
+/// });
+/// ```
+#[macro_export]
+macro_rules! assert_allocations {
+    (max = $max:expr, $body:block) => {{
+        let max: u64 = $max;
+        let counts = $crate::count_allocations(|| $body);
+        let total = counts.allocations + counts.reallocations;
+        if total > max {
+            panic!(
+                "expected at most {} allocations, found {}:\n{}",
+                max, total, counts
+            );
+        }
+    }};
+}
+
+impl Counter {
+    pub const fn new() -> Self {
+        Counter {
+            active: Cell::new(false),
+            counts: Cell::new(Counts {
+                allocations: 0,
+                deallocations: 0,
+                reallocations: 0,
+                bytes: 0,
+            }),
+            stacks: [const { Cell::new(None) }; MAX_COUNTED_STACKS],
+        }
+    }
+
+    fn enter(&self) -> Saved {
+        let stacks = std::array::from_fn(|index| self.stacks[index].take());
+        (self.active.replace(true), self.counts.take(), stacks)
+    }
+
+    fn exit(
+        &self,
+        (active, counts, stacks): Saved,
+    ) -> (Counts, [Option<StackId>; MAX_COUNTED_STACKS]) {
+        let inner = self.counts.replace(counts);
+        let inner_stacks = std::array::from_fn(|index| self.stacks[index].replace(stacks[index]));
+        self.active.set(active);
+        if active {
+            self.add(inner, &inner_stacks);
+        }
+        (inner, inner_stacks)
+    }
+
+    fn add(&self, counts: Counts, stacks: &[Option<StackId>]) {
+        let mut total = self.counts.get();
+        total.allocations += counts.allocations;
+        total.deallocations += counts.deallocations;
+        total.reallocations += counts.reallocations;
+        total.bytes += counts.bytes;
+        self.counts.set(total);
+        for &stack in stacks.iter().flatten() {
+            self.push_stack(stack);
+        }
+    }
+
+    fn push_stack(&self, stack: StackId) {
+        if let Some(slot) = self.stacks.iter().find(|slot| slot.get().is_none()) {
+            slot.set(Some(stack));
+        }
+    }
+
+    fn has_room(&self) -> bool {
+        self.stacks[MAX_COUNTED_STACKS - 1].get().is_none()
+    }
+}
+
+/// Count an allocator call on the current thread, if it is inside `count_allocations`.
+pub(crate) fn record(kind: Kind, size: usize, stack: &mut LazyStack) {
+    if !local::with(|local| local.counter.active.get()) {
+        return;
+    }
+    bookkeeping(|| {
+        let capture = kind != Kind::Dealloc && local::with(|local| local.counter.has_room());
+        let stack = if capture { stack.get() } else { None };
+        local::with(|local| {
+            let counter = &local.counter;
+            let mut counts = counter.counts.get();
+            match kind {
+                Kind::Alloc => counts.allocations += 1,
+                Kind::Dealloc => counts.deallocations += 1,
+                Kind::Realloc => counts.reallocations += 1,
+            }
+            if kind != Kind::Dealloc {
+                counts.bytes += size as u64;
+            }
+            counter.counts.set(counts);
+            if let Some(stack) = stack {
+                counter.push_stack(stack);
+            }
+        });
+    });
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Kind {
+    Alloc,
+    Dealloc,
+    Realloc,
+}
+
+impl fmt::Display for AllocCounts {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(
+            f,
+            "{} allocations, {} deallocations, {} reallocations, {} bytes",
+            self.allocations, self.deallocations, self.reallocations, self.bytes
+        )?;
+        for stack in &self.stacks {
+            write!(f, "allocated at:\n{}", stack)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,162 @@
+use std::cell::Cell;
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::StackId;
+
+/// A block of memory handed out by or returned to the inner allocator.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Block {
+    pub address: usize,
+    pub size: usize,
+    pub align: usize,
+}
+
+/// Metadata shared by every kind of allocation event.
+#[derive(Clone, Copy, Debug)]
+pub struct EventInfo {
+    /// A small integer identifying the thread that made the call, assigned in order of first use.
+    pub thread_id: u64,
+    /// The time of the call, in nanoseconds since the Unix epoch.
+    pub timestamp: u64,
+    /// The position of this event in the order of all events logged by the allocator.
+    pub sequence: u64,
+    /// The stack at the time of the call, if one was captured.
+    pub backtrace: Option<StackId>,
+    /// The number of allocations this event stands for. This is 1 unless sampling is enabled.
+    pub weight: f64,
+}
+
+/// A single call into the allocator.
+#[derive(Clone, Copy, Debug)]
+pub enum AllocEvent {
+    Alloc {
+        block: Block,
+        info: EventInfo,
+    },
+    AllocZeroed {
+        block: Block,
+        info: EventInfo,
+    },
+    Dealloc {
+        block: Block,
+        info: EventInfo,
+    },
+    Realloc {
+        old: Block,
+        new: Block,
+        info: EventInfo,
+    },
+}
+
+impl Block {
+    pub fn new(ptr: *mut u8, size: usize, align: usize) -> Self {
+        Block {
+            address: ptr as usize,
+            size,
+            align,
+        }
+    }
+
+    pub fn as_ptr(&self) -> *mut u8 {
+        self.address as *mut u8
+    }
+}
+
+impl Default for EventInfo {
+    fn default() -> Self {
+        EventInfo {
+            thread_id: 0,
+            timestamp: 0,
+            sequence: 0,
+            backtrace: None,
+            weight: 1.0,
+        }
+    }
+}
+
+impl AllocEvent {
+    /// The name of the allocator method that produced this event.
+    pub fn name(&self) -> &'static str {
+        match self {
+            AllocEvent::Alloc { .. } => "alloc",
+            AllocEvent::AllocZeroed { .. } => "alloc_zeroed",
+            AllocEvent::Dealloc { .. } => "dealloc",
+            AllocEvent::Realloc { .. } => "realloc",
+        }
+    }
+
+    pub fn info(&self) -> &EventInfo {
+        match self {
+            AllocEvent::Alloc { info, .. }
+            | AllocEvent::AllocZeroed { info, .. }
+            | AllocEvent::Dealloc { info, .. }
+            | AllocEvent::Realloc { info, .. } => info,
+        }
+    }
+
+    pub(crate) fn info_mut(&mut self) -> &mut EventInfo {
+        match self {
+            AllocEvent::Alloc { info, .. }
+            | AllocEvent::AllocZeroed { info, .. }
+            | AllocEvent::Dealloc { info, .. }
+            | AllocEvent::Realloc { info, .. } => info,
+        }
+    }
+
+    /// The block affected by this event. For reallocations this is the new block.
+    pub fn block(&self) -> Block {
+        match *self {
+            AllocEvent::Alloc { block, .. }
+            | AllocEvent::AllocZeroed { block, .. }
+            | AllocEvent::Dealloc { block, .. }
+            | AllocEvent::Realloc { new: block, .. } => block,
+        }
+    }
+}
+
+impl fmt::Display for Block {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "[address={:#x}, size={}, align={}]",
+            self.address, self.size, self.align
+        )
+    }
+}
+
+impl fmt::Display for AllocEvent {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AllocEvent::Realloc { old, new, .. } => write!(f, "realloc {} to {}", old, new)?,
+            _ => write!(f, "{} {}", self.name(), self.block())?,
+        }
+        if let Some(backtrace) = self.info().backtrace {
+            write!(f, " at:\n{}", backtrace)?;
+        }
+        Ok(())
+    }
+}
+
+/// Get the id of the current thread without allocating.
+pub(crate) fn thread_id() -> u64 {
+    static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+
+    thread_local! {
+        static ID: Cell<u64> = const { Cell::new(0) };
+    }
+
+    ID.with(|id| {
+        if id.get() == 0 {
+            id.set(NEXT_ID.fetch_add(1, Ordering::Relaxed));
+        }
+        id.get()
+    })
+}
+
+pub(crate) fn timestamp() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |time| time.as_nanos() as u64)
+}
@@ -0,0 +1,35 @@
+use std::sync::{Mutex, Once};
+
+const MAX_HOOKS: usize = 16;
+
+static HOOKS: Mutex<[Option<&'static dyn ExitHook>; MAX_HOOKS]> = Mutex::new([None; MAX_HOOKS]);
+
+/// Work to run when the process exits normally.
+pub(crate) trait ExitHook: Sync {
+    fn at_exit(&self);
+}
+
+/// Run `hook` when the process exits, after any hooks registered before it. Returns `false` if
+/// too many hooks have been registered.
+pub(crate) fn register(hook: &'static dyn ExitHook) -> bool {
+    static REGISTER: Once = Once::new();
+    REGISTER.call_once(|| unsafe {
+        libc::atexit(run_hooks);
+    });
+
+    let mut hooks = HOOKS.lock().unwrap_or_else(|err| err.into_inner());
+    match hooks.iter_mut().find(|slot| slot.is_none()) {
+        Some(slot) => {
+            *slot = Some(hook);
+            true
+        }
+        None => false,
+    }
+}
+
+extern "C" fn run_hooks() {
+    let hooks = *HOOKS.lock().unwrap_or_else(|err| err.into_inner());
+    for hook in hooks.iter().flatten() {
+        hook.at_exit();
+    }
+}
@@ -0,0 +1,230 @@
+use std::marker::PhantomData;
+use std::sync::atomic::{AtomicU64, AtomicU8, Ordering};
+
+use crate::local;
+use crate::StackId;
+
+/// When to make allocations fail.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FailurePolicy {
+    /// Never fail allocations.
+    Never,
+    /// Fail every `n`th allocation, counting from when the policy was set.
+    EveryNth(u64),
+    /// Fail each allocation with `probability`, using a random number generator seeded with
+    /// `seed`, so that the same sequence of allocations fails the same way every run.
+    Random { probability: f64, seed: u64 },
+    /// Fail every allocation of more than this many bytes.
+    LargerThan(usize),
+}
+
+/// Makes allocations on the current thread fail according to a policy until it is dropped.
+///
+/// A guard created while another is alive replaces its policy, and dropping it restores that
+/// policy. The outer guard must not be dropped first.
+#[must_use = "failures are only injected until the guard is dropped"]
+pub struct FailGuard {
+    previous: Option<Injector>,
+    _not_send: PhantomData<*const ()>,
+}
+
+const NEVER: u8 = 0;
+const EVERY_NTH: u8 = 1;
+const RANDOM: u8 = 2;
+const LARGER_THAN: u8 = 3;
+
+/// The process-wide failure policy and the number of failures injected.
+pub(crate) struct Faults {
+    kind: AtomicU8,
+    /// The parameter of the policy: `n`, the bits of the probability, or the size.
+    param: AtomicU64,
+    seed: AtomicU64,
+    count: AtomicU64,
+    rng: AtomicU64,
+    injected: AtomicU64,
+}
+
+/// A failure policy with its own state, for a single thread.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct Injector {
+    policy: FailurePolicy,
+    count: u64,
+    rng: u64,
+}
+
+impl Faults {
+    pub const fn new() -> Self {
+        Faults {
+            kind: AtomicU8::new(NEVER),
+            param: AtomicU64::new(0),
+            seed: AtomicU64::new(0),
+            count: AtomicU64::new(0),
+            rng: AtomicU64::new(0),
+            injected: AtomicU64::new(0),
+        }
+    }
+
+    pub fn policy(&self) -> FailurePolicy {
+        let param = self.param.load(Ordering::Relaxed);
+        match self.kind.load(Ordering::Acquire) {
+            EVERY_NTH => FailurePolicy::EveryNth(param),
+            RANDOM => FailurePolicy::Random {
+                probability: f64::from_bits(param),
+                seed: self.seed.load(Ordering::Relaxed),
+            },
+            LARGER_THAN => FailurePolicy::LargerThan(param as usize),
+            _ => FailurePolicy::Never,
+        }
+    }
+
+    pub fn set_policy(&self, policy: FailurePolicy) {
+        self.kind.store(NEVER, Ordering::Release);
+        let (kind, param) = match policy {
+            FailurePolicy::Never => return,
+            FailurePolicy::EveryNth(n) => (EVERY_NTH, n),
+            FailurePolicy::Random { probability, seed } => {
+                self.seed.store(seed, Ordering::Relaxed);
+                self.rng.store(seed_rng(seed), Ordering::Relaxed);
+                (RANDOM, probability.to_bits())
+            }
+            FailurePolicy::LargerThan(size) => (LARGER_THAN, size as u64),
+        };
+        self.count.store(0, Ordering::Relaxed);
+        self.param.store(param, Ordering::Relaxed);
+        self.kind.store(kind, Ordering::Release);
+    }
+
+    /// The number of allocations made to fail.
+    pub fn injected(&self) -> u64 {
+        self.injected.load(Ordering::Relaxed)
+    }
+
+    /// Decide whether to fail an allocation of `size` bytes, by the process-wide policy or the
+    /// current thread's. `stack` captures the current stack, and is only called if the
+    /// allocation is failed.
+    pub fn inject<F>(&self, size: usize, stack: F) -> bool
+    where
+        F: FnOnce() -> Option<StackId>,
+    {
+        if local::with(|local| local.busy.get()) {
+            return false;
+        }
+        let global = match self.kind.load(Ordering::Acquire) {
+            NEVER => false,
+            EVERY_NTH => {
+                let n = self.param.load(Ordering::Relaxed);
+                n != 0 && (self.count.fetch_add(1, Ordering::Relaxed) + 1).is_multiple_of(n)
+            }
+            RANDOM => {
+                let probability = f64::from_bits(self.param.load(Ordering::Relaxed));
+                let mut x = 0;
+                let _ = self
+                    .rng
+                    .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |state| {
+                        x = xorshift(state);
+                        Some(x)
+                    });
+                uniform(x) < probability
+            }
+            _ => size as u64 > self.param.load(Ordering::Relaxed),
+        };
+        let local = local::with(|local| {
+            let mut injector = local.injector.get()?;
+            let fail = injector.fail(size);
+            local.injector.set(Some(injector));
+            Some(fail)
+        });
+        if !global && local != Some(true) {
+            return false;
+        }
+        self.injected.fetch_add(1, Ordering::Relaxed);
+        local::bookkeeping(|| match stack() {
+            Some(stack) => eprint!(
+                "injected failure of an allocation of {} bytes at:\n{}",
+                size, stack
+            ),
+            None => eprintln!("injected failure of an allocation of {} bytes", size),
+        });
+        true
+    }
+}
+
+impl Injector {
+    fn new(policy: FailurePolicy) -> Self {
+        let rng = match policy {
+            FailurePolicy::Random { seed, .. } => seed_rng(seed),
+            _ => 0,
+        };
+        Injector {
+            policy,
+            count: 0,
+            rng,
+        }
+    }
+
+    fn fail(&mut self, size: usize) -> bool {
+        match self.policy {
+            FailurePolicy::Never => false,
+            FailurePolicy::EveryNth(n) => {
+                self.count += 1;
+                n != 0 && self.count.is_multiple_of(n)
+            }
+            FailurePolicy::Random { probability, .. } => {
+                self.rng = xorshift(self.rng);
+                uniform(self.rng) < probability
+            }
+            FailurePolicy::LargerThan(limit) => size > limit,
+        }
+    }
+}
+
+impl FailGuard {
+    pub(crate) fn new(policy: FailurePolicy) -> Self {
+        FailGuard {
+            previous: local::with(|local| local.injector.replace(Some(Injector::new(policy)))),
+            _not_send: PhantomData,
+        }
+    }
+}
+
+impl Drop for FailGuard {
+    fn drop(&mut self) {
+        local::with(|local| local.injector.set(self.previous))
+    }
+}
+
+/// xorshift state must be nonzero.
+fn seed_rng(seed: u64) -> u64 {
+    seed.wrapping_mul(0x9e37_79b9_7f4a_7c15) | 1
+}
+
+fn xorshift(mut x: u64) -> u64 {
+    x ^= x << 13;
+    x ^= x >> 7;
+    x ^= x << 17;
+    x
+}
+
+/// Map random bits to a float uniform in [0, 1).
+fn uniform(x: u64) -> f64 {
+    (x >> 11) as f64 / (1u64 << 53) as f64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::run_suppressed;
+
+    #[test]
+    fn exempt_only_bookkeeping() {
+        let faults = Faults::new();
+        faults.set_policy(FailurePolicy::LargerThan(8));
+        assert!(!faults.inject(8, || None));
+        assert!(run_suppressed(|| faults.inject(16, || None)));
+        assert_eq!(
+            local::bookkeeping(|| faults.inject(16, || None)),
+            Some(false)
+        );
+        assert_eq!(faults.injected(), 1);
+    }
+}
@@ -0,0 +1,262 @@
+use std::mem;
+use std::ops::{Bound, RangeBounds};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::thread;
+
+use crate::{local, Sink};
+
+/// Incremented whenever any thread name filter changes, so that the result cached by each thread
+/// can be invalidated.
+static GENERATION: AtomicU64 = AtomicU64::new(1);
+
+/// An inclusive range of block sizes to log.
+pub(crate) struct SizeFilter {
+    min: AtomicUsize,
+    max: AtomicUsize,
+}
+
+/// Sends every allocation at or above a size threshold to a separate sink.
+pub(crate) struct LargeAllocationAlert {
+    threshold: AtomicUsize,
+    sink: Mutex<Option<&'static (dyn Sink + Sync)>>,
+}
+
+/// Restricts logging to threads whose name matches a glob pattern.
+pub(crate) struct ThreadNameFilter {
+    /// The generation in which the pattern was set, or zero if there is no pattern.
+    generation: AtomicU64,
+    pattern: Mutex<Option<String>>,
+}
+
+impl SizeFilter {
+    pub const fn new() -> Self {
+        SizeFilter {
+            min: AtomicUsize::new(0),
+            max: AtomicUsize::new(usize::MAX),
+        }
+    }
+
+    pub fn set<R>(&self, sizes: R)
+    where
+        R: RangeBounds<usize>,
+    {
+        let min = match sizes.start_bound() {
+            Bound::Included(&start) => Some(start),
+            Bound::Excluded(&start) => start.checked_add(1),
+            Bound::Unbounded => Some(0),
+        };
+        let max = match sizes.end_bound() {
+            Bound::Included(&end) => Some(end),
+            Bound::Excluded(&end) => end.checked_sub(1),
+            Bound::Unbounded => Some(usize::MAX),
+        };
+        let (min, max) = match (min, max) {
+            (Some(min), Some(max)) => (min, max),
+            // The range is empty.
+            _ => (usize::MAX, 0),
+        };
+        self.min.store(min, Ordering::Relaxed);
+        self.max.store(max, Ordering::Relaxed);
+    }
+
+    pub fn matches(&self, size: usize) -> bool {
+        self.min.load(Ordering::Relaxed) <= size && size <= self.max.load(Ordering::Relaxed)
+    }
+}
+
+impl LargeAllocationAlert {
+    pub const fn new() -> Self {
+        LargeAllocationAlert {
+            threshold: AtomicUsize::new(usize::MAX),
+            sink: Mutex::new(None),
+        }
+    }
+
+    pub fn set(&self, threshold: usize, sink: Option<&'static (dyn Sink + Sync)>) {
+        let mut current = self.sink.lock().unwrap_or_else(|err| err.into_inner());
+        *current = sink;
+        let threshold = if sink.is_some() {
+            threshold
+        } else {
+            usize::MAX
+        };
+        self.threshold.store(threshold, Ordering::Relaxed);
+    }
+
+    /// Get the sink to alert if a block of `size` bytes is large.
+    pub fn sink(&self, size: usize) -> Option<&'static (dyn Sink + Sync)> {
+        if size < self.threshold.load(Ordering::Relaxed) {
+            return None;
+        }
+        *self.sink.lock().unwrap_or_else(|err| err.into_inner())
+    }
+}
+
+impl ThreadNameFilter {
+    pub const fn new() -> Self {
+        ThreadNameFilter {
+            generation: AtomicU64::new(0),
+            pattern: Mutex::new(None),
+        }
+    }
+
+    pub fn set(&self, pattern: Option<String>) {
+        let mut current = self.pattern.lock().unwrap_or_else(|err| err.into_inner());
+        let generation = match pattern {
+            Some(_) => GENERATION.fetch_add(1, Ordering::Relaxed) + 1,
+            None => 0,
+        };
+        let previous = mem::replace(&mut *current, pattern);
+        self.generation.store(generation, Ordering::Release);
+        // Freeing the previous pattern may be logged, and deciding whether to log it can take the
+        // lock.
+        drop(current);
+        drop(previous);
+    }
+
+    /// Whether the current thread's name matches the pattern. The result is cached per thread
+    /// until the pattern changes.
+    pub fn matches_current_thread(&self) -> bool {
+        let generation = self.generation.load(Ordering::Acquire);
+        if generation == 0 {
+            return true;
+        }
+        let (cached, matches) = local::with(|local| local.thread_name_match.get());
+        if cached == generation {
+            return matches;
+        }
+
+        // Getting the thread handle can allocate the first time, so threads are treated as not
+        // matching while their name is being looked up.
+        let matches = local::bookkeeping(|| {
+            let thread = thread::current();
+            let pattern = self.pattern.lock().unwrap_or_else(|err| err.into_inner());
+            match pattern.as_deref() {
+                Some(pattern) => glob(pattern, thread.name().unwrap_or("")),
+                None => true,
+            }
+        });
+        match matches {
+            Some(matches) => {
+                local::with(|local| local.thread_name_match.set((generation, matches)));
+                matches
+            }
+            None => false,
+        }
+    }
+}
+
+/// Match `text` against a pattern where `*` matches any sequence of characters and `?` matches
+/// any single character.
+fn glob(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+    let (mut p, mut t) = (0, 0);
+    // The position of the last `*` in the pattern and the text position it was tried at.
+    let mut backtrack = None;
+    while t < text.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                backtrack = Some((p, t));
+                p += 1;
+            }
+            Some(&c) if c == '?' || c == text[t] => {
+                p += 1;
+                t += 1;
+            }
+            _ => match backtrack {
+                Some((star, start)) => {
+                    p = star + 1;
+                    t = start + 1;
+                    backtrack = Some((star, start + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sizes<R>(range: R) -> Vec<usize>
+    where
+        R: RangeBounds<usize>,
+    {
+        let filter = SizeFilter::new();
+        filter.set(range);
+        [0, 1, 9, 10, 11, usize::MAX - 1, usize::MAX]
+            .iter()
+            .copied()
+            .filter(|&size| filter.matches(size))
+            .collect()
+    }
+
+    #[test]
+    fn size_filter_bounds() {
+        let all = vec![0, 1, 9, 10, 11, usize::MAX - 1, usize::MAX];
+        assert_eq!(sizes(..), all);
+        assert_eq!(sizes(10..), [10, 11, usize::MAX - 1, usize::MAX]);
+        assert_eq!(sizes(..10), [0, 1, 9]);
+        assert_eq!(sizes(..=10), [0, 1, 9, 10]);
+        assert_eq!(sizes(1..=10), [1, 9, 10]);
+        assert_eq!(sizes(usize::MAX..), [usize::MAX]);
+        assert_eq!(sizes((Bound::Excluded(9), Bound::Excluded(11))), [10]);
+    }
+
+    #[test]
+    fn size_filter_empty() {
+        assert_eq!(sizes(10..10), []);
+        assert_eq!(sizes(..0), []);
+        #[allow(clippy::reversed_empty_ranges)]
+        let reversed = 11..10;
+        assert_eq!(sizes(reversed), []);
+        assert_eq!(sizes((Bound::Excluded(usize::MAX), Bound::Unbounded)), []);
+    }
+
+    #[test]
+    fn glob_literal() {
+        assert!(glob("", ""));
+        assert!(glob("worker", "worker"));
+        assert!(!glob("worker", "worker-1"));
+        assert!(!glob("worker-1", "worker"));
+        assert!(!glob("", "main"));
+    }
+
+    #[test]
+    fn glob_wildcards() {
+        assert!(glob("*", ""));
+        assert!(glob("*", "main"));
+        assert!(glob("worker-*", "worker-"));
+        assert!(glob("worker-*", "worker-12"));
+        assert!(!glob("worker-*", "pool-worker-1"));
+        assert!(glob("*-worker", "pool-worker"));
+        assert!(glob("*worker*", "pool-worker-1"));
+        assert!(glob("worker-?", "worker-1"));
+        assert!(!glob("worker-?", "worker-12"));
+        assert!(!glob("worker-?", "worker-"));
+        assert!(glob("w*r*r", "worker"));
+        assert!(glob("a*b*c", "aXbYbZc"));
+        assert!(!glob("a*b*c", "aXbYcZ"));
+        assert!(glob("**", "a"));
+    }
+
+    #[test]
+    fn glob_unicode() {
+        assert!(glob("t?che", "tâche"));
+        assert!(glob("?", "é"));
+        assert!(!glob("??", "é"));
+    }
+
+    #[test]
+    fn size_filter_reset() {
+        let filter = SizeFilter::new();
+        filter.set(10..10);
+        filter.set(..);
+        assert!(filter.matches(0));
+        assert!(filter.matches(usize::MAX));
+    }
+}
@@ -0,0 +1,126 @@
+use std::marker::PhantomData;
+use std::process;
+use std::sync::atomic::{AtomicU64, AtomicU8, Ordering};
+
+use crate::local;
+use crate::StackId;
+
+/// What to do when a thread allocates inside a no-allocation zone.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ForbiddenAction {
+    /// Print the size of the allocation and a backtrace to stderr, then continue.
+    Log,
+    /// Print the size of the allocation and a backtrace to stderr, then abort the process.
+    Abort,
+    /// Only count the allocation and continue.
+    Record,
+}
+
+/// Forbids allocations on the current thread until it is dropped.
+///
+/// Dropping it forbids or permits allocations as they were when it was created, so a
+/// [`PermitGuard`] created inside the zone must be dropped before it.
+#[must_use = "allocations are only forbidden until the guard is dropped"]
+pub struct ForbidGuard {
+    previous: bool,
+    _not_send: PhantomData<*const ()>,
+}
+
+/// Permits allocations on the current thread inside a no-allocation zone until it is dropped.
+#[must_use = "allocations are only permitted until the guard is dropped"]
+pub struct PermitGuard {
+    previous: bool,
+    _not_send: PhantomData<*const ()>,
+}
+
+/// The action taken on forbidden allocations and the number seen.
+pub(crate) struct Forbidden {
+    action: AtomicU8,
+    count: AtomicU64,
+}
+
+impl Forbidden {
+    pub const fn new() -> Self {
+        Forbidden {
+            action: AtomicU8::new(ForbiddenAction::Log as u8),
+            count: AtomicU64::new(0),
+        }
+    }
+
+    pub fn action(&self) -> ForbiddenAction {
+        match self.action.load(Ordering::Relaxed) {
+            0 => ForbiddenAction::Log,
+            1 => ForbiddenAction::Abort,
+            _ => ForbiddenAction::Record,
+        }
+    }
+
+    pub fn set_action(&self, action: ForbiddenAction) {
+        self.action.store(action as u8, Ordering::Relaxed)
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count.load(Ordering::Relaxed)
+    }
+
+    /// Handle an allocation of `size` bytes made inside a no-allocation zone. `stack` captures
+    /// the current stack, and is only called if it is needed.
+    pub fn handle<F>(&self, size: usize, stack: F)
+    where
+        F: FnOnce() -> Option<StackId>,
+    {
+        self.count.fetch_add(1, Ordering::Relaxed);
+        let action = self.action();
+        if action == ForbiddenAction::Record {
+            return;
+        }
+        local::bookkeeping(|| {
+            match stack() {
+                Some(stack) => eprint!(
+                    "allocation of {} bytes in a no-allocation zone at:\n{}",
+                    size, stack
+                ),
+                None => eprintln!("allocation of {} bytes in a no-allocation zone", size),
+            };
+        });
+        if action == ForbiddenAction::Abort {
+            process::abort();
+        }
+    }
+}
+
+/// Whether allocations are forbidden on the current thread. Allocations made by the allocator's
+/// own bookkeeping, including by sinks, are always permitted.
+pub(crate) fn forbidden() -> bool {
+    local::with(|local| local.forbidden.get() && !local.busy.get())
+}
+
+impl ForbidGuard {
+    pub(crate) fn new() -> Self {
+        ForbidGuard {
+            previous: local::with(|local| local.forbidden.replace(true)),
+            _not_send: PhantomData,
+        }
+    }
+}
+
+impl Drop for ForbidGuard {
+    fn drop(&mut self) {
+        local::with(|local| local.forbidden.set(self.previous))
+    }
+}
+
+impl PermitGuard {
+    pub(crate) fn new() -> Self {
+        PermitGuard {
+            previous: local::with(|local| local.forbidden.replace(false)),
+            _not_send: PhantomData,
+        }
+    }
+}
+
+impl Drop for PermitGuard {
+    fn drop(&mut self) {
+        local::with(|local| local.forbidden.set(self.previous))
+    }
+}
@@ -0,0 +1,312 @@
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::cmp;
+use std::ops::RangeBounds;
+use std::ptr;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+
+use crate::filter::SizeFilter;
+use crate::table::Table;
+
+/// The maximum number of live blocks that can be served from guard pages at once. Selected
+/// allocations beyond this are served by the inner allocator.
+pub const MAX_GUARDED: usize = 1 << 16;
+
+const MAX_PROBES: usize = 256;
+
+const EMPTY: usize = 0;
+const REMOVED: usize = 1;
+
+/// An allocator that serves selected allocations from their own pages, placed so that the end of
+/// each block is followed by an inaccessible guard page. Writing past the end of a block faults
+/// immediately. When a block is freed its memory is returned to the operating system, but its
+/// addresses stay reserved and inaccessible and are never reused, so later accesses through
+/// dangling pointers fault too.
+///
+/// Blocks are placed as close to the guard page as their alignment allows, so an overflow into
+/// the padding of a block whose size is not a multiple of its alignment is not caught. Every
+/// block uses at least two pages of memory while it is live, and its pages of address space and
+/// a kernel mapping are never returned, so no allocation is guarded until
+/// [`set_sample_rate`](GuardPageAllocator::set_sample_rate) is called, and guard pages should be
+/// limited to the allocations being investigated with
+/// [`set_size_filter`](GuardPageAllocator::set_size_filter).
+///
+/// Other allocations, and allocations with an alignment larger than a page, are forwarded to the
+/// inner allocator. This is meant to be used as the inner allocator of a `LoggingAllocator`.
+pub struct GuardPageAllocator<A = System> {
+    size_filter: SizeFilter,
+    sample_rate: AtomicU64,
+    selected: AtomicU64,
+    guarded: AtomicU64,
+    registry: Registry,
+    allocator: A,
+}
+
+/// The addresses of live blocks served from guard pages.
+struct Registry {
+    slots: Table<AtomicUsize>,
+}
+
+impl GuardPageAllocator<System> {
+    pub const fn new() -> Self {
+        GuardPageAllocator::with_allocator(System)
+    }
+}
+
+impl Default for GuardPageAllocator<System> {
+    fn default() -> Self {
+        GuardPageAllocator::new()
+    }
+}
+
+impl<A> GuardPageAllocator<A> {
+    /// Create an allocator that forwards every allocation to `allocator` until a sample rate is
+    /// set.
+    pub const fn with_allocator(allocator: A) -> Self {
+        GuardPageAllocator {
+            size_filter: SizeFilter::new(),
+            sample_rate: AtomicU64::new(0),
+            selected: AtomicU64::new(0),
+            guarded: AtomicU64::new(0),
+            registry: Registry::new(),
+            allocator,
+        }
+    }
+
+    /// Only guard blocks with a size in `sizes`. For reallocations the new size is used.
+    pub fn set_size_filter<R>(&self, sizes: R)
+    where
+        R: RangeBounds<usize>,
+    {
+        self.size_filter.set(sizes)
+    }
+
+    /// Guard blocks of any size.
+    pub fn clear_size_filter(&self) {
+        self.size_filter.set(..)
+    }
+
+    /// Guard one in every `rate` allocations that pass the size filter. A rate of zero, the
+    /// default, disables guard pages.
+    pub fn set_sample_rate(&self, rate: u64) {
+        self.sample_rate.store(rate, Ordering::Relaxed)
+    }
+
+    pub fn sample_rate(&self) -> u64 {
+        self.sample_rate.load(Ordering::Relaxed)
+    }
+
+    /// The number of blocks that have been served from guard pages.
+    pub fn guarded_allocations(&self) -> u64 {
+        self.guarded.load(Ordering::Relaxed)
+    }
+
+    /// Decide whether to serve a block with `layout` from guard pages.
+    fn select(&self, layout: Layout) -> bool {
+        let rate = self.sample_rate();
+        if rate == 0
+            || layout.size() == 0
+            || layout.align() > page_size()
+            || !self.size_filter.matches(layout.size())
+        {
+            return false;
+        }
+        self.selected
+            .fetch_add(1, Ordering::Relaxed)
+            .is_multiple_of(rate)
+    }
+}
+
+impl<A> GuardPageAllocator<A>
+where
+    A: GlobalAlloc,
+{
+    /// Map pages for a block with `layout` followed by a guard page, returning a pointer to the
+    /// block. The pages are zeroed.
+    unsafe fn alloc_guarded(&self, layout: Layout) -> *mut u8 {
+        let (data, offset) = placement(layout);
+        let page = page_size();
+        let base = libc::mmap(
+            ptr::null_mut(),
+            data + page,
+            libc::PROT_READ | libc::PROT_WRITE,
+            libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+            -1,
+            0,
+        );
+        if base == libc::MAP_FAILED {
+            return ptr::null_mut();
+        }
+        let base = base as *mut u8;
+        if libc::mprotect(base.add(data) as *mut libc::c_void, page, libc::PROT_NONE) != 0 {
+            libc::munmap(base as *mut libc::c_void, data + page);
+            return ptr::null_mut();
+        }
+        let ptr = base.add(offset);
+        if !self.registry.insert(&self.allocator, ptr as usize) {
+            libc::munmap(base as *mut libc::c_void, data + page);
+            return ptr::null_mut();
+        }
+        self.guarded.fetch_add(1, Ordering::Relaxed);
+        ptr
+    }
+
+    /// Release the memory of a guarded block, leaving its pages mapped but inaccessible.
+    unsafe fn dealloc_guarded(&self, ptr: *mut u8, layout: Layout) {
+        let (data, offset) = placement(layout);
+        let base = ptr.sub(offset) as *mut libc::c_void;
+        // Mapping fresh inaccessible pages over the block discards its memory in one call.
+        let remapped = libc::mmap(
+            base,
+            data,
+            libc::PROT_NONE,
+            libc::MAP_PRIVATE | libc::MAP_ANONYMOUS | libc::MAP_FIXED | libc::MAP_NORESERVE,
+            -1,
+            0,
+        );
+        if remapped == libc::MAP_FAILED {
+            libc::mprotect(base, data, libc::PROT_NONE);
+            libc::madvise(base, data, libc::MADV_DONTNEED);
+        }
+    }
+}
+
+unsafe impl<A> GlobalAlloc for GuardPageAllocator<A>
+where
+    A: GlobalAlloc,
+{
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        if self.select(layout) {
+            let ptr = self.alloc_guarded(layout);
+            if !ptr.is_null() {
+                return ptr;
+            }
+        }
+        self.allocator.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        if self.registry.remove(ptr as usize) {
+            self.dealloc_guarded(ptr, layout)
+        } else {
+            self.allocator.dealloc(ptr, layout)
+        }
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        if self.select(layout) {
+            // Freshly mapped pages are already zeroed.
+            let ptr = self.alloc_guarded(layout);
+            if !ptr.is_null() {
+                return ptr;
+            }
+        }
+        self.allocator.alloc_zeroed(layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let new_layout = Layout::from_size_align_unchecked(new_size, layout.align());
+        let guarded = self.registry.contains(ptr as usize);
+        let selected = self.select(new_layout);
+        if !guarded && !selected {
+            return self.allocator.realloc(ptr, layout, new_size);
+        }
+        // Guarded blocks cannot be resized in place, since the guard page follows the block.
+        let mut new_ptr = if selected {
+            self.alloc_guarded(new_layout)
+        } else {
+            ptr::null_mut()
+        };
+        if new_ptr.is_null() {
+            if !guarded {
+                return self.allocator.realloc(ptr, layout, new_size);
+            }
+            new_ptr = self.allocator.alloc(new_layout);
+        }
+        if !new_ptr.is_null() {
+            ptr::copy_nonoverlapping(ptr, new_ptr, cmp::min(layout.size(), new_size));
+            self.dealloc(ptr, layout);
+        }
+        new_ptr
+    }
+}
+
+impl Registry {
+    const fn new() -> Self {
+        Registry {
+            slots: Table::new(MAX_GUARDED),
+        }
+    }
+
+    fn insert<A>(&self, allocator: &A, address: usize) -> bool
+    where
+        A: GlobalAlloc,
+    {
+        // All-zero slots are empty.
+        let slots = unsafe { self.slots.get_or_init(allocator) };
+        for slot in probe(slots, address) {
+            let current = slot.load(Ordering::Relaxed);
+            if (current == EMPTY || current == REMOVED)
+                && slot
+                    .compare_exchange(current, address, Ordering::Relaxed, Ordering::Relaxed)
+                    .is_ok()
+            {
+                return true;
+            }
+        }
+        false
+    }
+
+    fn find(&self, address: usize) -> Option<&AtomicUsize> {
+        let slots = self.slots.get()?;
+        for slot in probe(slots, address) {
+            match slot.load(Ordering::Relaxed) {
+                EMPTY => return None,
+                current if current == address => return Some(slot),
+                _ => (),
+            }
+        }
+        None
+    }
+
+    fn contains(&self, address: usize) -> bool {
+        self.find(address).is_some()
+    }
+
+    fn remove(&self, address: usize) -> bool {
+        match self.find(address) {
+            Some(slot) => slot
+                .compare_exchange(address, REMOVED, Ordering::Relaxed, Ordering::Relaxed)
+                .is_ok(),
+            None => false,
+        }
+    }
+}
+
+fn probe(slots: &[AtomicUsize], address: usize) -> impl Iterator<Item = &AtomicUsize> {
+    let start = (address as u64).wrapping_mul(0x9e37_79b9_7f4a_7c15) >> 32;
+    (0..MAX_PROBES).map(move |offset| &slots[(start as usize + offset) % slots.len()])
+}
+
+fn page_size() -> usize {
+    static PAGE_SIZE: AtomicUsize = AtomicUsize::new(0);
+    match PAGE_SIZE.load(Ordering::Relaxed) {
+        0 => {
+            let size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as usize;
+            PAGE_SIZE.store(size, Ordering::Relaxed);
+            size
+        }
+        size => size,
+    }
+}
+
+/// The number of bytes of accessible pages mapped for a block with `layout`, and the offset of
+/// the block within them.
+fn placement(layout: Layout) -> (usize, usize) {
+    let size = round_up(layout.size(), layout.align());
+    let data = round_up(size, page_size());
+    (data, data - size)
+}
+
+fn round_up(size: usize, multiple: usize) -> usize {
+    size.div_ceil(multiple) * multiple
+}
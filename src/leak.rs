@@ -0,0 +1,284 @@
+use std::cmp::Reverse;
+use std::fmt;
+
+use crate::live::LiveMap;
+use crate::{Block, StackId};
+
+/// The live allocations that were never freed, grouped by the stack that allocated them.
+#[derive(Clone, Debug, Default)]
+pub struct LeakReport {
+    /// Leaked blocks grouped by allocation stack, largest total size first.
+    pub leaks: Vec<Leak>,
+    /// The number of live blocks excluded because they are reachable from static memory.
+    pub reachable: usize,
+    /// The number of allocations that could not be tracked, and so may be missing from the report.
+    pub untracked: u64,
+}
+
+/// Leaked blocks that share an allocation stack.
+#[derive(Clone, Debug)]
+pub struct Leak {
+    pub stack: Option<StackId>,
+    pub blocks: Vec<Block>,
+    pub bytes: usize,
+}
+
+impl LeakReport {
+    pub(crate) fn new(live: &LiveMap) -> Self {
+        let mut blocks = Vec::new();
+        live.for_each(|address, record| {
+            if record.internal {
+                return;
+            }
+            let block = Block {
+                address,
+                size: record.size,
+                align: record.align,
+            };
+            blocks.push((block, record.stack));
+        });
+
+        let total = blocks.len();
+        blocks.sort_by_key(|(block, _)| block.address);
+        let reachable = reachable::find(&blocks);
+        let mut leaked: Vec<_> = blocks
+            .into_iter()
+            .zip(reachable)
+            .filter(|&(_, reachable)| !reachable)
+            .map(|(block, _)| block)
+            .collect();
+
+        leaked.sort_by_key(|&(block, stack)| (stack, block.address));
+        let mut leaks: Vec<Leak> = Vec::new();
+        for (block, stack) in leaked {
+            match leaks.last_mut() {
+                Some(leak) if leak.stack == stack => {
+                    leak.blocks.push(block);
+                    leak.bytes += block.size;
+                }
+                _ => leaks.push(Leak {
+                    stack,
+                    blocks: vec![block],
+                    bytes: block.size,
+                }),
+            }
+        }
+        leaks.sort_by_key(|leak| Reverse(leak.bytes));
+
+        let leaked: usize = leaks.iter().map(|leak| leak.blocks.len()).sum();
+        LeakReport {
+            leaks,
+            reachable: total - leaked,
+            untracked: live.overflowed(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.leaks.is_empty()
+    }
+}
+
+impl fmt::Display for LeakReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let blocks: usize = self.leaks.iter().map(|leak| leak.blocks.len()).sum();
+        let bytes: usize = self.leaks.iter().map(|leak| leak.bytes).sum();
+        write!(
+            f,
+            "leaked {} bytes in {} blocks from {} callsites ({} reachable blocks excluded",
+            bytes,
+            blocks,
+            self.leaks.len(),
+            self.reachable
+        )?;
+        if self.untracked != 0 {
+            write!(f, ", {} allocations untracked", self.untracked)?;
+        }
+        writeln!(f, ")")?;
+        for leak in &self.leaks {
+            write!(f, "{}", leak)?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for Leak {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(
+            f,
+            "leaked {} bytes in {} blocks:",
+            self.bytes,
+            self.blocks.len()
+        )?;
+        for block in &self.blocks {
+            writeln!(f, "    {}", block)?;
+        }
+        match self.stack {
+            Some(stack) => write!(f, "allocated at:\n{}", stack),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod reachable {
+    use std::ffi::c_void;
+    use std::mem;
+    use std::ops::Range;
+    use std::ptr;
+
+    use crate::{Block, StackId};
+
+    /// Mark every block reachable from a writable segment of a loaded object. `blocks` must be
+    /// sorted by address.
+    pub fn find(blocks: &[(Block, Option<StackId>)]) -> Vec<bool> {
+        let mut reachable = vec![false; blocks.len()];
+        let mut pending = Vec::new();
+        for segment in segments() {
+            scan(segment, blocks, &mut reachable, &mut pending);
+        }
+        while let Some(index) = pending.pop() {
+            let block = blocks[index].0;
+            let range = block.address..block.address + block.size;
+            scan(range, blocks, &mut reachable, &mut pending);
+        }
+        reachable
+    }
+
+    fn scan(
+        range: Range<usize>,
+        blocks: &[(Block, Option<StackId>)],
+        reachable: &mut [bool],
+        pending: &mut Vec<usize>,
+    ) {
+        let word = mem::size_of::<usize>();
+        let mut address = (range.start + word - 1) & !(word - 1);
+        while address + word <= range.end {
+            let value = unsafe { ptr::read_volatile(address as *const usize) };
+            if let Some(index) = containing(blocks, value) {
+                if !reachable[index] {
+                    reachable[index] = true;
+                    pending.push(index);
+                }
+            }
+            address += word;
+        }
+    }
+
+    fn containing(blocks: &[(Block, Option<StackId>)], value: usize) -> Option<usize> {
+        let index = blocks
+            .partition_point(|(block, _)| block.address <= value)
+            .checked_sub(1)?;
+        let block = blocks[index].0;
+        if value == block.address || value < block.address + block.size {
+            Some(index)
+        } else {
+            None
+        }
+    }
+
+    fn segments() -> Vec<Range<usize>> {
+        unsafe extern "C" fn callback(
+            info: *mut libc::dl_phdr_info,
+            _: libc::size_t,
+            data: *mut c_void,
+        ) -> libc::c_int {
+            let info = &*info;
+            let segments = &mut *(data as *mut Vec<Range<usize>>);
+            for index in 0..info.dlpi_phnum as usize {
+                let header = &*info.dlpi_phdr.add(index);
+                if header.p_type == libc::PT_LOAD && header.p_flags & libc::PF_W != 0 {
+                    let start = (info.dlpi_addr + header.p_vaddr) as usize;
+                    segments.push(start..start + header.p_memsz as usize);
+                }
+            }
+            0
+        }
+
+        let mut segments = Vec::new();
+        unsafe {
+            libc::dl_iterate_phdr(
+                Some(callback),
+                &mut segments as *mut Vec<Range<usize>> as *mut c_void,
+            );
+        }
+        segments
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+mod reachable {
+    use crate::{Block, StackId};
+
+    /// Static memory cannot be located on this platform, so no block is known to be reachable.
+    pub fn find(blocks: &[(Block, Option<StackId>)]) -> Vec<bool> {
+        vec![false; blocks.len()]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::alloc::System;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::*;
+    use crate::live::Record;
+
+    static ROOT: AtomicUsize = AtomicUsize::new(0);
+
+    fn record(size: usize, stack: Option<StackId>) -> Record {
+        Record {
+            stack,
+            internal: false,
+            ..Record::internal(size, 8, false)
+        }
+    }
+
+    #[test]
+    fn report() {
+        let blocks: Vec<*mut [usize; 4]> =
+            (0..5).map(|_| Box::into_raw(Box::new([0; 4]))).collect();
+        let addresses: Vec<usize> = blocks.iter().map(|&block| block as usize).collect();
+        // The first block is reachable from a static, and the second through the first.
+        let first = blocks[0];
+        unsafe { (*first)[1] = addresses[1] };
+        ROOT.store(addresses[0], Ordering::SeqCst);
+
+        let live = LiveMap::new();
+        live.init(&System);
+        let stack = Some(StackId::from_raw(7));
+        live.insert(addresses[0], record(32, stack));
+        live.insert(addresses[1], record(32, stack));
+        live.insert(addresses[2], record(32, stack));
+        live.insert(addresses[3], record(16, stack));
+        live.insert(addresses[4], record(24, None));
+        live.insert(1, Record::internal(8, 8, false));
+        let report = LeakReport::new(&live);
+        ROOT.store(0, Ordering::SeqCst);
+
+        assert_eq!(report.reachable, 2);
+        assert_eq!(report.leaks.len(), 2);
+        assert_eq!(report.leaks[0].stack, stack);
+        assert_eq!(report.leaks[0].bytes, 48);
+        let mut leaked: Vec<_> = report.leaks[0]
+            .blocks
+            .iter()
+            .map(|block| block.address)
+            .collect();
+        leaked.sort_unstable();
+        let mut expected = [addresses[2], addresses[3]];
+        expected.sort_unstable();
+        assert_eq!(leaked, expected);
+        assert_eq!(report.leaks[1].stack, None);
+        assert_eq!(report.leaks[1].bytes, 24);
+
+        let text = report.to_string();
+        assert!(text.starts_with("leaked 72 bytes in 3 blocks from 2 callsites (2 reachable"));
+        for (&address, size) in addresses[2..].iter().zip([32, 16, 24]) {
+            let line = format!("    [address={:#x}, size={}, align=8]\n", address, size);
+            assert!(text.contains(&line), "{}", text);
+        }
+        for block in blocks {
+            drop(unsafe { Box::from_raw(block) });
+        }
+    }
+}
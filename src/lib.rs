@@ -1,16 +1,108 @@
 use std::alloc::{GlobalAlloc, Layout, System};
-use std::backtrace::Backtrace;
-use std::cell::Cell;
-use std::fmt;
-use std::sync::atomic::{AtomicBool, Ordering};
+use std::cmp;
+use std::ops::RangeBounds;
+use std::ptr;
+use std::sync::atomic::{AtomicBool, AtomicU16, AtomicU64, Ordering};
 
-#[cfg(feature = "warn")]
-const WARNING_THRESHOLD: usize = 1_000_000;
+use self::budget::Budget;
+use self::callsite::Callsites;
+use self::capture::Backtraces;
+use self::exit::ExitHook;
+use self::fault::Faults;
+use self::filter::{LargeAllocationAlert, SizeFilter, ThreadNameFilter};
+use self::forbid::Forbidden;
+use self::live::{Entry, LiveMap, Record};
+use self::local::bookkeeping;
+use self::quarantine::{Quarantine, Quarantined};
+use self::region::{RegionId, Regions};
+use self::sample::Sampler;
+use self::stack::LazyStack;
+use self::stats::Counters;
+
+mod background;
+mod budget;
+mod callsite;
+mod capture;
+mod check;
+mod count;
+mod event;
+mod exit;
+mod fault;
+mod filter;
+mod forbid;
+#[cfg(target_os = "linux")]
+mod guard;
+mod leak;
+mod live;
+mod local;
+mod module;
+mod quarantine;
+mod red_zone;
+mod region;
+mod sample;
+mod sink;
+mod stack;
+mod stats;
+mod symbolize;
+mod table;
+mod trace;
+
+pub use self::background::{BackgroundSink, BUFFER_CAPACITY, MAX_THREADS};
+pub use self::budget::{BudgetAction, BudgetExceeded};
+pub use self::callsite::{Callsite, CallsiteOrder};
+pub use self::capture::BacktracePolicy;
+pub use self::count::{count_allocations, AllocCounts, MAX_COUNTED_STACKS};
+pub use self::event::{AllocEvent, Block, EventInfo};
+pub use self::fault::{FailGuard, FailurePolicy};
+pub use self::forbid::{ForbidGuard, ForbiddenAction, PermitGuard};
+#[cfg(target_os = "linux")]
+pub use self::guard::{GuardPageAllocator, MAX_GUARDED};
+pub use self::leak::{Leak, LeakReport};
+pub use self::live::MAX_LIVE_ALLOCATIONS;
+pub use self::local::SuppressGuard;
+pub use self::module::Module;
+pub use self::quarantine::MAX_QUARANTINED;
+pub use self::red_zone::RED_ZONE;
+pub use self::region::{RegionGuard, RegionStats, MAX_REGIONS};
+pub use self::sink::{Sink, StderrSink, WriteSink};
+pub use self::stack::{StackId, Unwinder, MAX_FRAMES, STACK_CAPACITY};
+pub use self::stats::Stats;
+pub use self::symbolize::{Symbol, Symbolizer};
+pub use self::trace::{TraceEvent, TraceReader, TraceStackId, TraceWriter};
+
+/// The value of `alloc_fill` when new blocks are not filled.
+const NO_FILL: u16 = u16::MAX;
 
 /// A wrapper allocator that logs messages on allocation.
-pub struct LoggingAllocator<A = System> {
+pub struct LoggingAllocator<A = System, S = StderrSink> {
     enabled: AtomicBool,
+    thread_name_filter: ThreadNameFilter,
+    size_filter: SizeFilter,
+    large_allocation_alert: LargeAllocationAlert,
+    backtraces: Backtraces,
+    sequence: AtomicU64,
+    sampler: Sampler,
+    counters: Counters,
+    callsite_tracking: AtomicBool,
+    callsites: Callsites,
+    leak_tracking: AtomicBool,
+    leak_report_at_exit: AtomicBool,
+    free_checking: AtomicBool,
+    bad_frees: AtomicU64,
+    layout_mismatches: AtomicU64,
+    red_zones: AtomicBool,
+    red_zones_used: AtomicBool,
+    corrupted_blocks: AtomicU64,
+    quarantine: Quarantine,
+    use_after_free_writes: AtomicU64,
+    alloc_fill: AtomicU16,
+    regions: Regions,
+    forbidden: Forbidden,
+    faults: Faults,
+    budget: Budget,
+    live: LiveMap,
     allocator: A,
+    sink: S,
 }
 
 impl LoggingAllocator<System> {
@@ -21,12 +113,52 @@ impl LoggingAllocator<System> {
 
 impl<A> LoggingAllocator<A> {
     pub const fn with_allocator(allocator: A, enabled: bool) -> Self {
+        LoggingAllocator::with_sink(allocator, StderrSink, enabled)
+    }
+}
+
+impl<A, S> LoggingAllocator<A, S> {
+    pub const fn with_sink(allocator: A, sink: S, enabled: bool) -> Self {
         LoggingAllocator {
             enabled: AtomicBool::new(enabled),
+            thread_name_filter: ThreadNameFilter::new(),
+            size_filter: SizeFilter::new(),
+            large_allocation_alert: LargeAllocationAlert::new(),
+            backtraces: Backtraces::new(),
+            sequence: AtomicU64::new(0),
+            sampler: Sampler::new(),
+            counters: Counters::new(),
+            callsite_tracking: AtomicBool::new(false),
+            callsites: Callsites::new(),
+            leak_tracking: AtomicBool::new(false),
+            leak_report_at_exit: AtomicBool::new(false),
+            free_checking: AtomicBool::new(false),
+            bad_frees: AtomicU64::new(0),
+            layout_mismatches: AtomicU64::new(0),
+            red_zones: AtomicBool::new(false),
+            red_zones_used: AtomicBool::new(false),
+            corrupted_blocks: AtomicU64::new(0),
+            quarantine: Quarantine::new(),
+            use_after_free_writes: AtomicU64::new(0),
+            alloc_fill: AtomicU16::new(NO_FILL),
+            regions: Regions::new(),
+            forbidden: Forbidden::new(),
+            faults: Faults::new(),
+            budget: Budget::new(),
+            live: LiveMap::new(),
             allocator,
+            sink,
         }
     }
 
+    pub fn sink(&self) -> &S {
+        &self.sink
+    }
+
+    pub fn allocator(&self) -> &A {
+        &self.allocator
+    }
+
     pub fn enable_logging(&self) {
         self.enabled.store(true, Ordering::SeqCst)
     }
@@ -38,99 +170,965 @@ impl<A> LoggingAllocator<A> {
     pub fn logging_enabled(&self) -> bool {
         self.enabled.load(Ordering::SeqCst)
     }
+
+    /// Enable logging on the current thread, regardless of the global setting.
+    pub fn enable_logging_on_current_thread(&self) {
+        local::with(|local| local.logging.set(Some(true)))
+    }
+
+    /// Disable logging on the current thread, regardless of the global setting.
+    pub fn disable_logging_on_current_thread(&self) {
+        local::with(|local| local.logging.set(Some(false)))
+    }
+
+    /// Make the current thread follow the global setting again.
+    pub fn reset_logging_on_current_thread(&self) {
+        local::with(|local| local.logging.set(None))
+    }
+
+    /// Whether events on the current thread are logged.
+    ///
+    /// A setting made for the thread with [`enable_logging_on_current_thread`] or
+    /// [`disable_logging_on_current_thread`] takes precedence. Otherwise events are logged if
+    /// logging is enabled globally and, if a thread name filter is set, the thread's name matches
+    /// it.
+    ///
+    /// [`enable_logging_on_current_thread`]: LoggingAllocator::enable_logging_on_current_thread
+    /// [`disable_logging_on_current_thread`]: LoggingAllocator::disable_logging_on_current_thread
+    pub fn logging_enabled_on_current_thread(&self) -> bool {
+        match local::with(|local| local.logging.get()) {
+            Some(enabled) => enabled,
+            None => self.logging_enabled() && self.thread_name_filter.matches_current_thread(),
+        }
+    }
+
+    /// Only log events from threads whose name matches `pattern`, in which `*` matches any
+    /// sequence of characters and `?` matches any single character. Unnamed threads match as if
+    /// their name was empty.
+    pub fn set_thread_name_filter(&self, pattern: &str) {
+        self.thread_name_filter.set(Some(pattern.to_owned()))
+    }
+
+    pub fn clear_thread_name_filter(&self) {
+        self.thread_name_filter.set(None)
+    }
+
+    /// Disable logging on the current thread until the returned guard is dropped.
+    pub fn suppress(&self) -> SuppressGuard {
+        SuppressGuard::new()
+    }
+
+    /// Only log events for blocks with a size in `sizes`. For reallocations the new size is used.
+    pub fn set_size_filter<R>(&self, sizes: R)
+    where
+        R: RangeBounds<usize>,
+    {
+        self.size_filter.set(sizes)
+    }
+
+    /// Log events for blocks of any size.
+    pub fn clear_size_filter(&self) {
+        self.size_filter.set(..)
+    }
+
+    /// Send every allocation or reallocation of at least `threshold` bytes to `sink`, with a
+    /// backtrace. Alerts are sent even while logging is disabled.
+    pub fn set_large_allocation_alert(&self, threshold: usize, sink: &'static (dyn Sink + Sync)) {
+        self.large_allocation_alert.set(threshold, Some(sink))
+    }
+
+    pub fn clear_large_allocation_alert(&self) {
+        self.large_allocation_alert.set(usize::MAX, None)
+    }
+
+    /// The mean number of bytes allocated between samples, or zero if sampling is disabled.
+    pub fn sample_interval(&self) -> usize {
+        self.sampler.interval()
+    }
+
+    /// Stop sampling, so that every allocation is logged and tracked again.
+    pub fn disable_sampling(&self) {
+        self.sampler.set_interval(0)
+    }
+
+    /// Get a snapshot of the allocation counters. These are kept even while logging is disabled.
+    pub fn stats(&self) -> Stats {
+        self.counters.snapshot()
+    }
+
+    /// Reset the allocation counters. The count of bytes in use is not affected.
+    pub fn reset_stats(&self) {
+        self.counters.reset()
+    }
+
+    pub fn callsite_tracking_enabled(&self) -> bool {
+        self.callsite_tracking.load(Ordering::SeqCst)
+    }
+
+    pub fn disable_callsite_tracking(&self) {
+        self.callsite_tracking.store(false, Ordering::SeqCst)
+    }
+
+    /// How stacks are captured. The default is [`Unwinder::Backtrace`].
+    pub fn unwinder(&self) -> Unwinder {
+        stack::unwinder()
+    }
+
+    /// Set how stacks are captured. Stacks are interned in a table shared by the whole process,
+    /// so this applies to every allocator.
+    pub fn set_unwinder(&self, unwinder: Unwinder) {
+        stack::set_unwinder(unwinder)
+    }
+
+    /// Which logged events carry a backtrace. The default is [`BacktracePolicy::Always`].
+    pub fn backtrace_policy(&self) -> BacktracePolicy {
+        self.backtraces.policy()
+    }
+
+    /// Set which logged events carry a backtrace. Large allocation alerts always carry one, and
+    /// stacks are still captured for callsite tracking, leak tracking, and free checking.
+    pub fn set_backtrace_policy(&self, policy: BacktracePolicy) {
+        self.backtraces.set_policy(policy)
+    }
+
+    /// The maximum number of frames printed for a stack. The default is [`MAX_FRAMES`].
+    pub fn max_backtrace_frames(&self) -> usize {
+        stack::max_depth()
+    }
+
+    /// Set the maximum number of frames printed for a stack, up to [`MAX_FRAMES`]. The frames in
+    /// `alloc` that are left out of printed stacks don't count towards the limit, and the
+    /// outermost frames of deeper stacks are cut off. Stacks are still captured and traced in
+    /// full. Like the unwinder, this applies to every allocator.
+    pub fn set_max_backtrace_frames(&self, frames: usize) {
+        stack::set_max_depth(frames)
+    }
+
+    /// Get the `n` callsites with the highest totals, in the given order.
+    pub fn top_callsites(&self, n: usize, order: CallsiteOrder) -> Vec<Callsite> {
+        self.callsites.top(n, order)
+    }
+
+    pub fn leak_tracking_enabled(&self) -> bool {
+        self.leak_tracking.load(Ordering::SeqCst)
+    }
+
+    /// Stop recording new allocations for the leak report. Allocations already recorded are still
+    /// reported until they are freed.
+    pub fn disable_leak_tracking(&self) {
+        self.leak_tracking.store(false, Ordering::SeqCst)
+    }
+
+    /// Find every tracked allocation that is still live and not reachable from static memory.
+    pub fn leaks(&self) -> LeakReport {
+        bookkeeping(|| LeakReport::new(&self.live)).unwrap_or_default()
+    }
+
+    /// Print the leak report to stderr.
+    pub fn report_leaks(&self) {
+        bookkeeping(|| eprint!("{}", LeakReport::new(&self.live)));
+    }
+
+    pub fn free_checking_enabled(&self) -> bool {
+        self.free_checking.load(Ordering::SeqCst)
+    }
+
+    pub fn disable_free_checking(&self) {
+        self.free_checking.store(false, Ordering::SeqCst)
+    }
+
+    /// The number of double frees and frees of unknown blocks, and the same for reallocations,
+    /// found by free checking.
+    pub fn bad_frees(&self) -> u64 {
+        self.bad_frees.load(Ordering::Relaxed)
+    }
+
+    /// The number of frees and reallocations found by free checking that passed a different
+    /// layout than the block was allocated with.
+    pub fn layout_mismatches(&self) -> u64 {
+        self.layout_mismatches.load(Ordering::Relaxed)
+    }
+
+    pub fn red_zones_enabled(&self) -> bool {
+        self.red_zones.load(Ordering::SeqCst)
+    }
+
+    /// Stop surrounding new blocks with red zones. Blocks that already have them are still
+    /// checked when they are freed.
+    pub fn disable_red_zones(&self) {
+        self.red_zones.store(false, Ordering::SeqCst)
+    }
+
+    /// The number of blocks with damaged red zones found when they were freed or reallocated, or
+    /// by [`check_heap`](LoggingAllocator::check_heap).
+    pub fn corrupted_blocks(&self) -> u64 {
+        self.corrupted_blocks.load(Ordering::Relaxed)
+    }
+
+    /// Check the red zones of every live block, printing each damaged block to stderr with the
+    /// stack that allocated it. Returns the number of damaged blocks.
+    pub fn check_heap(&self) -> usize {
+        let addresses = bookkeeping(|| {
+            let mut addresses = Vec::new();
+            self.live.for_each(|address, record| {
+                if record.red_zone {
+                    addresses.push(address);
+                }
+            });
+            addresses
+        })
+        .unwrap_or_default();
+        let mut damaged = 0;
+        for address in addresses {
+            let found = self.live.inspect(address, |record| {
+                let layout =
+                    unsafe { Layout::from_size_align_unchecked(record.size, record.align) };
+                unsafe { red_zone::check(address as *const u8, layout) }
+                    .map(|corruption| (record, corruption))
+            });
+            if let Some(Some((record, corruption))) = found {
+                damaged += 1;
+                self.corrupted_blocks.fetch_add(1, Ordering::Relaxed);
+                check::report_corruption(address, record, corruption, None);
+            }
+        }
+        damaged
+    }
+
+    /// The size limit of the quarantine, or zero if freed blocks are not quarantined.
+    pub fn quarantine_max_bytes(&self) -> usize {
+        self.quarantine.max_bytes()
+    }
+
+    /// The total size of the freed blocks currently held in quarantine.
+    pub fn quarantined_bytes(&self) -> usize {
+        self.quarantine.bytes()
+    }
+
+    /// The number of quarantined blocks found to have been written to after they were freed.
+    pub fn use_after_free_writes(&self) -> u64 {
+        self.use_after_free_writes.load(Ordering::Relaxed)
+    }
+
+    /// The byte that new memory is filled with, if it is set.
+    pub fn alloc_fill(&self) -> Option<u8> {
+        match self.alloc_fill.load(Ordering::Relaxed) {
+            NO_FILL => None,
+            pattern => Some(pattern as u8),
+        }
+    }
+
+    /// Fill every block allocated with `alloc`, and the part of a block added by `realloc`, with
+    /// `pattern`, so that reads of uninitialized memory see a recognizable value. Blocks
+    /// allocated with `alloc_zeroed` are still zeroed. This is independent of logging.
+    pub fn set_alloc_fill(&self, pattern: u8) {
+        self.alloc_fill.store(u16::from(pattern), Ordering::Relaxed)
+    }
+
+    /// Leave new memory as the inner allocator returns it.
+    pub fn clear_alloc_fill(&self) {
+        self.alloc_fill.store(NO_FILL, Ordering::Relaxed)
+    }
+
+    /// Fill bytes `from..to` of a newly allocated block with the fill pattern, if it is set.
+    unsafe fn fill(&self, ptr: *mut u8, from: usize, to: usize) {
+        if let Some(pattern) = self.alloc_fill() {
+            ptr::write_bytes(ptr.add(from), pattern, to - from);
+        }
+    }
+
+    /// Get the counters for the region with this name, if it has been entered.
+    pub fn region_stats(&self, name: &str) -> Option<RegionStats> {
+        self.regions.find(name)
+    }
+
+    /// Get the counters for every region that has been entered.
+    pub fn regions(&self) -> Vec<RegionStats> {
+        self.regions.all()
+    }
+
+    /// Forbid allocations and reallocations on the current thread until the returned guard is
+    /// dropped. Each one made is handled according to [`forbidden_action`], but still succeeds
+    /// unless the action is to abort. Allocations made by sinks are permitted, but suppressing
+    /// logging does not permit allocations.
+    ///
+    /// [`forbidden_action`]: LoggingAllocator::forbidden_action
+    pub fn forbid_allocations(&self) -> ForbidGuard {
+        ForbidGuard::new()
+    }
+
+    /// Permit allocations on the current thread inside a no-allocation zone until the returned
+    /// guard is dropped.
+    pub fn permit_allocations(&self) -> PermitGuard {
+        PermitGuard::new()
+    }
+
+    /// What is done with allocations made inside a no-allocation zone. The default is
+    /// [`ForbiddenAction::Log`].
+    pub fn forbidden_action(&self) -> ForbiddenAction {
+        self.forbidden.action()
+    }
+
+    pub fn set_forbidden_action(&self, action: ForbiddenAction) {
+        self.forbidden.set_action(action)
+    }
+
+    /// The number of allocations and reallocations made inside no-allocation zones.
+    pub fn forbidden_allocations(&self) -> u64 {
+        self.forbidden.count()
+    }
+
+    /// The policy for making allocations fail on every thread. The default is
+    /// [`FailurePolicy::Never`].
+    pub fn failure_policy(&self) -> FailurePolicy {
+        self.faults.policy()
+    }
+
+    /// Make allocations and reallocations on every thread return null according to `policy`.
+    /// Each failure is printed to stderr with the current stack.
+    ///
+    /// Allocations made by sinks never fail, but those made while logging is suppressed can.
+    pub fn set_failure_policy(&self, policy: FailurePolicy) {
+        self.faults.set_policy(policy)
+    }
+
+    /// Also make allocations and reallocations on the current thread fail according to `policy`
+    /// until the returned guard is dropped.
+    pub fn inject_failures(&self, policy: FailurePolicy) -> FailGuard {
+        FailGuard::new(policy)
+    }
+
+    /// The number of allocations and reallocations made to fail.
+    pub fn injected_failures(&self) -> u64 {
+        self.faults.injected()
+    }
+
+    /// The maximum number of bytes in use, if a limit is set.
+    pub fn memory_limit(&self) -> Option<usize> {
+        self.budget.limit()
+    }
+
+    pub fn clear_memory_limit(&self) {
+        self.budget.clear()
+    }
+
+    /// The number of allocations and reallocations that would have exceeded the memory limit.
+    pub fn memory_limit_exceeded(&self) -> u64 {
+        self.budget.exceeded()
+    }
 }
 
-/// Execute a closure without logging on allocations.
-pub fn run_guarded<F>(f: F)
+impl<A, S> LoggingAllocator<A, S>
 where
-    F: FnOnce(),
+    A: GlobalAlloc,
 {
-    thread_local! {
-        static GUARD: Cell<bool> = Cell::new(false);
+    /// Limit the number of bytes in use to `limit`. When an allocation or reallocation would take
+    /// the bytes in use over the limit, it is printed to stderr with the current stack and the
+    /// callsites with the most live bytes, and then handled according to `action`.
+    ///
+    /// Setting a limit enables callsite tracking, so only allocations made since then are
+    /// attributed to callsites, and clearing it leaves tracking enabled. Allocations made by sinks
+    /// are not limited, but those made while logging is suppressed are. Each call is checked
+    /// against the bytes in use before it, so concurrent allocations can together exceed the
+    /// limit.
+    pub fn set_memory_limit(&self, limit: usize, action: BudgetAction) {
+        self.enable_callsite_tracking();
+        self.budget.set(limit, action)
+    }
+
+    /// Start capturing the stack of every allocation and aggregating totals for each unique
+    /// stack. The tables used are allocated from the inner allocator on first use.
+    pub fn enable_callsite_tracking(&self) {
+        self.callsites.init(&self.allocator);
+        self.live.init(&self.allocator);
+        self.callsite_tracking.store(true, Ordering::SeqCst)
+    }
+
+    /// Start recording the address and stack of every allocation, so that allocations that are
+    /// never freed can be reported by [`leaks`](LoggingAllocator::leaks).
+    pub fn enable_leak_tracking(&self) {
+        self.live.init(&self.allocator);
+        self.leak_tracking.store(true, Ordering::SeqCst)
+    }
+
+    /// Start recording every live allocation, so that frees and reallocations of blocks that are
+    /// not live can be caught before they reach the inner allocator. Each one is printed to stderr
+    /// with the current stack and, for a double free, the stacks that allocated and freed the
+    /// block. A double free is then dropped: the free does nothing and the reallocation fails.
+    ///
+    /// Frees and reallocations of live blocks are also checked to pass the same size and
+    /// alignment the block was allocated with. A mismatch is printed with the stacks of the
+    /// allocation and the free, but the call is still forwarded.
+    ///
+    /// Blocks allocated before checking was enabled are unknown, as are invalid pointers. Freeing
+    /// an unknown block is reported, but the call is still forwarded, since the block may be
+    /// valid. Checking should be enabled at the start of `main` to avoid these reports; the
+    /// standard library frees a few blocks allocated before `main` when the process exits. Once
+    /// the table of live allocations has been full, unknown blocks are no longer reported. A
+    /// double free is only caught until the address is allocated again.
+    pub fn enable_free_checking(&self) {
+        self.live.init(&self.allocator);
+        self.free_checking.store(true, Ordering::SeqCst)
+    }
+
+    /// Surround every new block with [`RED_ZONE`] canary bytes on each side, which are checked
+    /// when the block is freed or reallocated and by [`check_heap`]. Damage is printed to stderr
+    /// as an underflow or overflow with the stack that allocated the block.
+    ///
+    /// Blocks with red zones are allocated from the inner allocator with room for the canaries and
+    /// the requested alignment, and are moved to a new block whenever they are reallocated.
+    /// Every block with red zones is recorded in the table of live allocations, and blocks are
+    /// allocated without them while the table is full.
+    ///
+    /// [`check_heap`]: LoggingAllocator::check_heap
+    pub fn enable_red_zones(&self) {
+        self.live.init(&self.allocator);
+        self.red_zones_used.store(true, Ordering::SeqCst);
+        self.red_zones.store(true, Ordering::SeqCst)
+    }
+
+    /// Fill every freed block with a poison pattern and hold it in quarantine instead of returning
+    /// it to the inner allocator, until the total size of the blocks in quarantine exceeds
+    /// `max_bytes` or [`MAX_QUARANTINED`] blocks are held. The oldest blocks are released first,
+    /// after checking that their poison is intact. A damaged block is printed to stderr as a use
+    /// after free with the stacks that allocated and freed it, if they were captured.
+    ///
+    /// While quarantine is enabled, blocks are moved to a new block whenever they are
+    /// reallocated, so that the old block is quarantined too.
+    pub fn enable_quarantine(&self, max_bytes: usize) {
+        self.quarantine.init(&self.allocator);
+        self.quarantine.set_max_bytes(max_bytes)
+    }
+
+    /// Stop quarantining freed blocks, and release every block in quarantine.
+    pub fn disable_quarantine(&self) {
+        self.quarantine.set_max_bytes(0);
+        self.flush_quarantine();
+    }
+
+    /// Check and release every block in quarantine. Returns the number of blocks that were
+    /// written to after they were freed.
+    pub fn flush_quarantine(&self) -> usize {
+        let mut damaged = 0;
+        while let Some(block) = self.quarantine.pop(true) {
+            if !unsafe { self.release(block) } {
+                damaged += 1;
+            }
+        }
+        damaged
     }
 
-    GUARD.with(|guard| {
-        if !guard.replace(true) {
-            f();
-            guard.set(false)
+    /// Add a freed block to the quarantine, releasing the oldest blocks to make room.
+    unsafe fn quarantine(&self, block: Quarantined) {
+        quarantine::poison(block.ptr, block.layout.size());
+        let mut block = block;
+        while let Err(rejected) = self.quarantine.push(block) {
+            match self.quarantine.pop(true) {
+                Some(oldest) => {
+                    self.release(oldest);
+                    block = rejected;
+                }
+                None => {
+                    self.release(rejected);
+                    return;
+                }
+            }
+        }
+        while let Some(oldest) = self.quarantine.pop(false) {
+            self.release(oldest);
         }
-    })
+    }
+
+    /// Return a block that has left quarantine to the inner allocator, reporting it if its poison
+    /// is damaged. Returns whether the poison was intact.
+    unsafe fn release(&self, block: Quarantined) -> bool {
+        let damage = quarantine::check(block.ptr, block.layout.size());
+        if let Some(damage) = damage {
+            self.use_after_free_writes.fetch_add(1, Ordering::Relaxed);
+            check::report_use_after_free(
+                block.ptr as usize,
+                block.layout.size(),
+                block.record,
+                damage,
+            );
+        }
+        match block.red_zone {
+            Some(layout) => red_zone::dealloc(&self.allocator, block.ptr, layout),
+            None => self.allocator.dealloc(block.ptr, block.layout),
+        }
+        damage.is_none()
+    }
+
+    /// Only log events and aggregate callsites for a sample of allocations, taken on average once
+    /// every `interval` bytes allocated on each thread. Each sampled event carries a weight, the
+    /// number of allocations it stands for, so totals derived from the samples are unbiased.
+    ///
+    /// Deallocations are logged only if they free a sampled block. Statistics and leak tracking
+    /// still see every allocation.
+    pub fn enable_sampling(&self, interval: usize) {
+        self.live.init(&self.allocator);
+        self.sampler.set_interval(interval)
+    }
+
+    /// Attribute every allocation made on the current thread to the region `name` until the
+    /// returned guard is dropped.
+    ///
+    /// Blocks are recorded in the table of live allocations, so that freeing them is credited to
+    /// their region on any thread. Entering the first region allocates that table from the inner
+    /// allocator: [`MAX_LIVE_ALLOCATIONS`] entries of 64 bytes each on 64-bit targets, or 16 MiB.
+    pub fn region(&self, name: &'static str) -> RegionGuard<'_> {
+        self.live.init(&self.allocator);
+        self.regions.enter(name)
+    }
 }
 
-unsafe impl<A> GlobalAlloc for LoggingAllocator<A>
+impl<A, S> LoggingAllocator<A, S>
 where
-    A: GlobalAlloc,
+    A: Sync,
+    S: Sync,
 {
-    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
-        #[cfg(feature = "warn")]
-        {
-            if layout.size() > WARNING_THRESHOLD {
-                eprintln!("large allocation at {:?}", backtrace::Backtrace::new());
+    /// Print the leak report to stderr when the process exits.
+    pub fn report_leaks_at_exit(&'static self) {
+        if !self.leak_report_at_exit.swap(true, Ordering::SeqCst) {
+            exit::register(self);
+        }
+    }
+}
+
+impl<A, S> ExitHook for LoggingAllocator<A, S>
+where
+    A: Sync,
+    S: Sync,
+{
+    fn at_exit(&self) {
+        if self.leak_report_at_exit.load(Ordering::SeqCst) {
+            self.report_leaks();
+        }
+    }
+}
+
+impl<A, S> LoggingAllocator<A, S>
+where
+    S: Sink,
+{
+    /// Start a call that allocates `size` bytes, deciding whether to sample it. `caller` is an
+    /// address in the frame of the entry point, from [`stack::here`].
+    fn call(&self, size: usize, caller: usize) -> Call {
+        Call {
+            stack: LazyStack::new(caller),
+            weight: self.sampler.sample(size),
+            region: region::current(),
+            red_zone: false,
+        }
+    }
+
+    /// Allocate a block from the inner allocator, surrounded by red zones if they are enabled.
+    unsafe fn alloc_block(&self, layout: Layout, zeroed: bool, call: &mut Call) -> *mut u8
+    where
+        A: GlobalAlloc,
+    {
+        if self.red_zones_enabled() {
+            let ptr = red_zone::alloc(&self.allocator, layout, zeroed);
+            // A block with red zones must be recorded, or it could not be found to free it.
+            let record = Record::internal(layout.size(), layout.align(), true);
+            if ptr.is_null() || self.live.insert(ptr as usize, record) {
+                call.red_zone = !ptr.is_null();
+                return ptr;
             }
+            red_zone::dealloc(&self.allocator, ptr, layout);
         }
-        let ptr = self.allocator.alloc(layout);
-        if self.logging_enabled() {
-            run_guarded(|| {
-                eprintln!("alloc {}", Fmt(ptr, layout.size(), layout.align(), true));
-            });
+        if zeroed {
+            self.allocator.alloc_zeroed(layout)
+        } else {
+            self.allocator.alloc(layout)
         }
-        ptr
     }
 
-    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
-        self.allocator.dealloc(ptr, layout);
-        if self.logging_enabled() {
-            run_guarded(|| eprintln!("dealloc {}", Fmt(ptr, layout.size(), layout.align(), true),));
+    /// Free a block to the inner allocator, or to the quarantine if it is enabled. `red_zone` is
+    /// the layout the block was allocated with, if it has red zones, and `record` is what was
+    /// recorded about it while it was live.
+    unsafe fn free_block(
+        &self,
+        ptr: *mut u8,
+        layout: Layout,
+        red_zone: Option<Layout>,
+        record: Option<Record>,
+    ) where
+        A: GlobalAlloc,
+    {
+        let block = Quarantined {
+            ptr,
+            layout,
+            red_zone,
+            record,
+        };
+        if self.quarantine.is_enabled() {
+            self.quarantine(block);
+        } else {
+            match red_zone {
+                Some(layout) => red_zone::dealloc(&self.allocator, ptr, layout),
+                None => self.allocator.dealloc(ptr, layout),
+            }
         }
     }
 
-    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
-        let ptr = self.allocator.alloc_zeroed(layout);
-        if self.logging_enabled() {
-            run_guarded(|| {
-                eprintln!("alloc_zeroed {}", Fmt(ptr, layout.size(), layout.align(), true));
-            });
+    /// Look up `block` before it is freed or reallocated, if anything needs to know about it.
+    fn entry(&self, block: Block) -> Entry {
+        if self.free_checking_enabled() || self.red_zones_used.load(Ordering::Relaxed) {
+            self.live.get(block.address)
+        } else {
+            Entry::Unknown
         }
-        ptr
     }
 
-    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
-        #[cfg(feature = "warn")]
+    fn check_forbidden(&self, size: usize, call: &mut Call) {
+        if forbid::forbidden() {
+            self.forbidden.handle(size, || call.stack.get());
+        }
+    }
+
+    /// Decide whether to make an allocation of `size` bytes, which adds `growth` bytes to the
+    /// bytes in use, fail.
+    fn inject_failure(&self, size: usize, growth: usize, call: &mut Call) -> bool {
+        if self.faults.inject(size, || call.stack.get()) {
+            return true;
+        }
+        let bytes_in_use = self.counters.bytes_in_use();
+        !self.budget.check(
+            size,
+            bytes_in_use,
+            growth,
+            || call.stack.get(),
+            |n| {
+                let mut callsites = self.top_callsites(n, CallsiteOrder::LiveBytes);
+                callsites.retain(|callsite| callsite.live_bytes > 0);
+                callsites
+            },
+        )
+    }
+
+    /// Check that `block` is live and has the layout it was allocated with before it is freed or
+    /// reallocated, reporting it if not. Returns whether the call should be forwarded to the inner
+    /// allocator.
+    fn check_free(&self, block: Block, entry: &Entry, call: &mut Call) -> bool {
+        if !self.free_checking_enabled() {
+            return true;
+        }
+        let record = match *entry {
+            Entry::Live(record) => {
+                if record.size != block.size || record.align != block.align {
+                    self.layout_mismatches.fetch_add(1, Ordering::Relaxed);
+                    check::report_layout_mismatch(block, record, &mut call.stack);
+                }
+                return true;
+            }
+            Entry::Freed(record) => Some(record),
+            Entry::Unknown if self.live.overflowed() > 0 => return true,
+            Entry::Unknown => None,
+        };
+        self.bad_frees.fetch_add(1, Ordering::Relaxed);
+        check::report_bad_free(block, record, &mut call.stack);
+        // An unknown block may have been allocated before checking was enabled, so only a double
+        // free is dropped.
+        record.is_none()
+    }
+
+    /// If `block` has red zones, check them, reporting any damage. Returns the layout the block was
+    /// allocated with.
+    fn check_red_zone(&self, block: Block, entry: &Entry, call: &mut Call) -> Option<Layout> {
+        let record = match *entry {
+            Entry::Live(record) if record.red_zone => record,
+            _ => return None,
+        };
+        let layout = unsafe { Layout::from_size_align_unchecked(record.size, record.align) };
+        if let Some(corruption) = unsafe { red_zone::check(block.as_ptr(), layout) } {
+            self.corrupted_blocks.fetch_add(1, Ordering::Relaxed);
+            check::report_corruption(block.address, record, corruption, Some(&mut call.stack));
+        }
+        Some(layout)
+    }
+
+    fn record_alloc(&self, block: Block, call: &mut Call) {
+        self.counters.alloc(block.size);
+        count::record(count::Kind::Alloc, block.size, &mut call.stack);
+        self.track(block, call);
+    }
+
+    fn record_dealloc(&self, block: Block, call: &mut Call) -> Option<Record> {
+        self.counters.dealloc(block.size);
+        count::record(count::Kind::Dealloc, block.size, &mut call.stack);
+        self.untrack(block, call)
+    }
+
+    fn record_realloc(&self, old: Block, new: Block, call: &mut Call) -> Option<Record> {
+        self.counters.realloc(old.size, new.size);
+        count::record(count::Kind::Realloc, new.size, &mut call.stack);
+        let record = self.untrack(old, &mut Call::new(call.stack.caller()));
+        self.track(new, call);
+        record
+    }
+
+    fn track(&self, block: Block, call: &mut Call) {
+        let callsites = self.callsite_tracking_enabled() && call.weight.is_some();
+        let checking = self.free_checking_enabled();
+        if !callsites
+            && !checking
+            && !call.red_zone
+            && !self.leak_tracking_enabled()
+            && !self.sampler.is_enabled()
+            && call.region.is_none()
         {
-            if new_size > WARNING_THRESHOLD {
-                eprintln!("large reallocation at {:?}", backtrace::Backtrace::new());
+            return;
+        }
+        let tracked = bookkeeping(|| {
+            // Blocks with red zones keep their stack so that damage found later can be traced to
+            // the allocation.
+            let stack = if callsites || checking || call.red_zone || self.leak_tracking_enabled() {
+                call.stack.get()
+            } else {
+                None
+            };
+            let counted = match (stack, call.weight) {
+                (Some(id), Some(weight)) if callsites => {
+                    self.callsites.alloc(id, block.size, weight);
+                    true
+                }
+                _ => false,
+            };
+            if let Some(region) = call.region {
+                self.regions.alloc(region, block.size);
             }
+            let record = Record {
+                size: block.size,
+                align: block.align,
+                stack,
+                weight: call.weight.unwrap_or(0.0),
+                counted,
+                region: call.region,
+                internal: false,
+                freed: None,
+                red_zone: call.red_zone,
+            };
+            if call.red_zone {
+                self.live.update(block.address, record);
+            } else {
+                self.live.insert(block.address, record);
+            }
+        });
+        if tracked.is_none() && checking && !call.red_zone {
+            // Blocks allocated by our own bookkeeping are recorded only so that freeing them is
+            // not reported.
+            let record = Record::internal(block.size, block.align, false);
+            self.live.insert(block.address, record);
         }
-        let new_ptr = self.allocator.realloc(ptr, layout, new_size);
-        if self.logging_enabled() {
-            run_guarded(|| {
-                eprintln!(
-                    "realloc {} to {}",
-                    Fmt(ptr, layout.size(), layout.align(), false),
-                    Fmt(new_ptr, new_size, layout.align(), true)
-                );
-            });
+    }
+
+    fn untrack(&self, block: Block, call: &mut Call) -> Option<Record> {
+        let freed = if self.free_checking_enabled() {
+            bookkeeping(|| call.stack.get()).flatten()
+        } else {
+            None
+        };
+        let record = self.live.remove(block.address, freed);
+        if let Some(record) = record {
+            if let (Some(id), true) = (record.stack, record.counted) {
+                self.callsites.dealloc(id, record.size, record.weight);
+            }
+            if let Some(region) = record.region {
+                self.regions.dealloc(region, record.size);
+            }
         }
-        new_ptr
+        if self.sampler.is_enabled() {
+            call.weight = record
+                .map(|record| record.weight)
+                .filter(|&weight| weight > 0.0);
+        }
+        record
+    }
+
+    fn log<F>(&self, call: &mut Call, event: F)
+    where
+        F: FnOnce(EventInfo) -> AllocEvent,
+    {
+        let mut event = event(EventInfo::default());
+        let size = event.block().size;
+        let logged = call.weight.is_some()
+            && self.size_filter.matches(size)
+            && self.logging_enabled_on_current_thread();
+        let alert = match event {
+            AllocEvent::Dealloc { .. } => None,
+            _ => self.large_allocation_alert.sink(size),
+        };
+        if (!logged && alert.is_none()) || local::suppressed() {
+            return;
+        }
+
+        // Allocations made by sinks are the allocator's own, so they must not be logged, counted
+        // or tracked.
+        bookkeeping(|| {
+            let backtrace = if self.backtraces.capture(&event) {
+                call.stack.get()
+            } else {
+                None
+            };
+            *event.info_mut() = EventInfo {
+                thread_id: event::thread_id(),
+                timestamp: event::timestamp(),
+                sequence: self.sequence.fetch_add(1, Ordering::Relaxed),
+                backtrace,
+                weight: call.weight.unwrap_or(1.0),
+            };
+            if logged {
+                self.sink.log(&event);
+            }
+            if let Some(alert) = alert {
+                if backtrace.is_none() {
+                    event.info_mut().backtrace = call.stack.get();
+                }
+                alert.log(&event);
+            }
+        });
+    }
+}
+
+/// State for a single call into the allocator.
+struct Call {
+    stack: LazyStack,
+    /// The sampling weight of the block being allocated or freed, or `None` if it was not sampled.
+    weight: Option<f64>,
+    /// The region the call is attributed to.
+    region: Option<RegionId>,
+    /// Whether the block being allocated has red zones.
+    red_zone: bool,
+}
+
+impl Call {
+    fn new(caller: usize) -> Self {
+        Call {
+            stack: LazyStack::new(caller),
+            weight: Some(1.0),
+            region: region::current(),
+            red_zone: false,
+        }
+    }
+}
+
+/// Execute a closure without logging on allocations.
+///
+/// If logging is already suppressed on this thread the closure is not run at all. Use
+/// [`run_suppressed`] to always run it.
+pub fn run_guarded<F>(f: F)
+where
+    F: FnOnce(),
+{
+    if !local::suppressed() {
+        run_suppressed(f)
     }
 }
 
-struct Fmt(*mut u8, usize, usize, bool);
+/// Execute a closure without logging on allocations, returning its result.
+pub fn run_suppressed<F, R>(f: F) -> R
+where
+    F: FnOnce() -> R,
+{
+    let _guard = SuppressGuard::new();
+    f()
+}
 
-impl fmt::Display for Fmt {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        if self.3 {
-            write!(
-                f,
-                "[address={:p}, size={}, align={}] at:\n{:}",
-                self.0, self.1, self.2, Backtrace::capture()
-            )
+unsafe impl<A, S> GlobalAlloc for LoggingAllocator<A, S>
+where
+    A: GlobalAlloc,
+    S: Sink,
+{
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let mut call = self.call(layout.size(), stack::here());
+        self.check_forbidden(layout.size(), &mut call);
+        let ptr = if self.inject_failure(layout.size(), layout.size(), &mut call) {
+            ptr::null_mut()
         } else {
-            write!(
-                f,
-                "[address={:p}, size={}, align={}]",
-                self.0, self.1, self.2
-            )
+            self.alloc_block(layout, false, &mut call)
+        };
+        let block = Block::new(ptr, layout.size(), layout.align());
+        if !ptr.is_null() {
+            self.fill(ptr, 0, layout.size());
+            self.record_alloc(block, &mut call);
         }
+        self.log(&mut call, |info| AllocEvent::Alloc { block, info });
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        let block = Block::new(ptr, layout.size(), layout.align());
+        let mut call = Call::new(stack::here());
+        let entry = self.entry(block);
+        if !self.check_free(block, &entry, &mut call) {
+            return;
+        }
+        let red_zone = self.check_red_zone(block, &entry, &mut call);
+        let record = self.record_dealloc(block, &mut call);
+        self.free_block(ptr, layout, red_zone, record);
+        self.log(&mut call, |info| AllocEvent::Dealloc { block, info });
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        let mut call = self.call(layout.size(), stack::here());
+        self.check_forbidden(layout.size(), &mut call);
+        let ptr = if self.inject_failure(layout.size(), layout.size(), &mut call) {
+            ptr::null_mut()
+        } else {
+            self.alloc_block(layout, true, &mut call)
+        };
+        let block = Block::new(ptr, layout.size(), layout.align());
+        if !ptr.is_null() {
+            self.record_alloc(block, &mut call);
+        }
+        self.log(&mut call, |info| AllocEvent::AllocZeroed { block, info });
+        ptr
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let mut call = self.call(new_size, stack::here());
+        self.check_forbidden(new_size, &mut call);
+        let old = Block::new(ptr, layout.size(), layout.align());
+        let entry = self.entry(old);
+        if !self.check_free(old, &entry, &mut call) {
+            return ptr::null_mut();
+        }
+        // Blocks with red zones are moved, so that the red zones of both blocks are placed and
+        // checked. While quarantine is enabled, blocks are moved so that the old block is
+        // quarantined.
+        let moved = self.red_zones_enabled()
+            || self.quarantine.is_enabled()
+            || matches!(entry, Entry::Live(record) if record.red_zone);
+        let new_ptr =
+            if self.inject_failure(new_size, new_size.saturating_sub(layout.size()), &mut call) {
+                ptr::null_mut()
+            } else if moved {
+                let new_layout = Layout::from_size_align_unchecked(new_size, layout.align());
+                let new_ptr = self.alloc_block(new_layout, false, &mut call);
+                if !new_ptr.is_null() {
+                    ptr::copy_nonoverlapping(ptr, new_ptr, cmp::min(layout.size(), new_size));
+                }
+                new_ptr
+            } else {
+                self.allocator.realloc(ptr, layout, new_size)
+            };
+        let new = Block::new(new_ptr, new_size, layout.align());
+        if !new_ptr.is_null() {
+            if new_size > layout.size() {
+                self.fill(new_ptr, layout.size(), new_size);
+            }
+            let red_zone = if moved {
+                self.check_red_zone(old, &entry, &mut call)
+            } else {
+                None
+            };
+            let record = self.record_realloc(old, new, &mut call);
+            if moved {
+                self.free_block(ptr, layout, red_zone, record);
+            }
+        }
+        self.log(&mut call, |info| AllocEvent::Realloc { old, new, info });
+        new_ptr
     }
 }
@@ -0,0 +1,270 @@
+use std::alloc::GlobalAlloc;
+use std::cell::UnsafeCell;
+use std::hint;
+use std::mem::MaybeUninit;
+use std::sync::atomic::{AtomicU64, AtomicU8, AtomicUsize, Ordering};
+
+use crate::region::RegionId;
+use crate::table::Table;
+use crate::StackId;
+
+/// The maximum number of live allocations that can be tracked at once. Allocations beyond this
+/// are not tracked.
+pub const MAX_LIVE_ALLOCATIONS: usize = 1 << 18;
+
+const MAX_PROBES: usize = 256;
+
+const EMPTY: u8 = 0;
+const LIVE: u8 = 1;
+const FREED: u8 = 2;
+const LOCKED: u8 = 3;
+
+/// What is remembered about each live allocation.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct Record {
+    pub size: usize,
+    pub align: usize,
+    pub stack: Option<StackId>,
+    /// The sampling weight of the allocation, or zero if it was not sampled.
+    pub weight: f64,
+    /// Whether the allocation was added to the callsite totals for `stack`.
+    pub counted: bool,
+    /// The region the allocation was made in.
+    pub region: Option<RegionId>,
+    /// Whether the allocation was made by the allocator's own bookkeeping.
+    pub internal: bool,
+    /// The stack that freed the allocation, kept in the tombstone once it is freed.
+    pub freed: Option<StackId>,
+    /// Whether the allocation is surrounded by red zones.
+    pub red_zone: bool,
+}
+
+impl Record {
+    /// A record for a block allocated by the allocator's own bookkeeping.
+    pub fn internal(size: usize, align: usize, red_zone: bool) -> Self {
+        Record {
+            size,
+            align,
+            stack: None,
+            weight: 0.0,
+            counted: false,
+            region: None,
+            internal: true,
+            freed: None,
+            red_zone,
+        }
+    }
+}
+
+/// The state of an address in a `LiveMap`.
+pub(crate) enum Entry {
+    Live(Record),
+    /// The address was freed, and has not been allocated again since.
+    Freed(Record),
+    /// The address was never recorded, or its tombstone has been reused.
+    Unknown,
+}
+
+/// An allocation-free hash map from the address of each live allocation to its `Record`.
+///
+/// Each slot has its own spin lock. Removed entries are kept as reusable tombstones so that probe
+/// sequences are never broken.
+pub(crate) struct LiveMap {
+    slots: Table<Slot>,
+    overflowed: AtomicU64,
+}
+
+struct Slot {
+    state: AtomicU8,
+    address: AtomicUsize,
+    record: UnsafeCell<MaybeUninit<Record>>,
+}
+
+// `record` is only accessed while holding the slot's lock.
+unsafe impl Sync for Slot {}
+
+impl LiveMap {
+    pub const fn new() -> Self {
+        LiveMap {
+            slots: Table::new(MAX_LIVE_ALLOCATIONS),
+            overflowed: AtomicU64::new(0),
+        }
+    }
+
+    pub fn init<A>(&self, allocator: &A)
+    where
+        A: GlobalAlloc,
+    {
+        // All-zero slots are empty.
+        unsafe { self.slots.get_or_init(allocator) };
+    }
+
+    /// The number of allocations that could not be tracked because the map was full.
+    pub fn overflowed(&self) -> u64 {
+        self.overflowed.load(Ordering::Relaxed)
+    }
+
+    pub fn insert(&self, address: usize, record: Record) -> bool {
+        let slots = match self.slots.get() {
+            Some(slots) => slots,
+            None => return false,
+        };
+        for slot in probe(slots, address) {
+            if slot.state.load(Ordering::Relaxed) == LIVE {
+                continue;
+            }
+            let state = slot.lock();
+            if state == LIVE {
+                slot.unlock(state);
+                continue;
+            }
+            slot.address.store(address, Ordering::Relaxed);
+            unsafe { (*slot.record.get()).write(record) };
+            slot.unlock(LIVE);
+            return true;
+        }
+        self.overflowed.fetch_add(1, Ordering::Relaxed);
+        false
+    }
+
+    /// Replace the record of the live allocation at `address`. Returns whether it was found.
+    pub fn update(&self, address: usize, record: Record) -> bool {
+        self.with_live(address, |slot| {
+            slot.write(record);
+        })
+        .is_some()
+    }
+
+    /// Call `f` with the record of the live allocation at `address` while holding its lock, so
+    /// that the allocation cannot be removed until `f` returns. `f` must not allocate.
+    pub fn inspect<R>(&self, address: usize, f: impl FnOnce(Record) -> R) -> Option<R> {
+        self.with_live(address, |slot| f(unsafe { slot.assume_init() }))
+    }
+
+    fn with_live<R>(
+        &self,
+        address: usize,
+        f: impl FnOnce(&mut MaybeUninit<Record>) -> R,
+    ) -> Option<R> {
+        let slots = self.slots.get()?;
+        for slot in probe(slots, address) {
+            match slot.state.load(Ordering::Relaxed) {
+                EMPTY => return None,
+                LIVE if slot.address.load(Ordering::Relaxed) == address => (),
+                _ => continue,
+            }
+            let state = slot.lock();
+            if state != LIVE || slot.address.load(Ordering::Relaxed) != address {
+                slot.unlock(state);
+                continue;
+            }
+            let result = f(unsafe { &mut *slot.record.get() });
+            slot.unlock(state);
+            return Some(result);
+        }
+        None
+    }
+
+    /// Find the entry for `address`, without changing it.
+    pub fn get(&self, address: usize) -> Entry {
+        let slots = match self.slots.get() {
+            Some(slots) => slots,
+            None => return Entry::Unknown,
+        };
+        for slot in probe(slots, address) {
+            match slot.state.load(Ordering::Relaxed) {
+                EMPTY => return Entry::Unknown,
+                _ if slot.address.load(Ordering::Relaxed) != address => continue,
+                _ => (),
+            }
+            let state = slot.lock();
+            if slot.address.load(Ordering::Relaxed) != address {
+                slot.unlock(state);
+                continue;
+            }
+            let entry = match state {
+                LIVE => Entry::Live(unsafe { (*slot.record.get()).assume_init() }),
+                FREED => Entry::Freed(unsafe { (*slot.record.get()).assume_init() }),
+                _ => Entry::Unknown,
+            };
+            slot.unlock(state);
+            return entry;
+        }
+        Entry::Unknown
+    }
+
+    /// Remove the record for `address`, leaving a tombstone that remembers the stack that freed
+    /// it.
+    pub fn remove(&self, address: usize, freed: Option<StackId>) -> Option<Record> {
+        let slots = self.slots.get()?;
+        for slot in probe(slots, address) {
+            match slot.state.load(Ordering::Relaxed) {
+                EMPTY => return None,
+                _ if slot.address.load(Ordering::Relaxed) != address => continue,
+                _ => (),
+            }
+            let state = slot.lock();
+            if slot.address.load(Ordering::Relaxed) != address {
+                slot.unlock(state);
+                continue;
+            }
+            if state != LIVE {
+                slot.unlock(state);
+                return None;
+            }
+            let record = unsafe { (*slot.record.get()).assume_init_mut() };
+            record.freed = freed;
+            let record = *record;
+            slot.unlock(FREED);
+            return Some(record);
+        }
+        None
+    }
+
+    /// Call `f` with the address and record of every live allocation.
+    pub fn for_each(&self, mut f: impl FnMut(usize, Record)) {
+        for slot in self.slots.get().unwrap_or(&[]) {
+            if slot.state.load(Ordering::Relaxed) != LIVE {
+                continue;
+            }
+            let state = slot.lock();
+            let entry = if state == LIVE {
+                Some((slot.address.load(Ordering::Relaxed), unsafe {
+                    (*slot.record.get()).assume_init()
+                }))
+            } else {
+                None
+            };
+            slot.unlock(state);
+            if let Some((address, record)) = entry {
+                f(address, record);
+            }
+        }
+    }
+}
+
+impl Slot {
+    fn lock(&self) -> u8 {
+        loop {
+            let state = self.state.load(Ordering::Relaxed);
+            if state != LOCKED
+                && self
+                    .state
+                    .compare_exchange_weak(state, LOCKED, Ordering::Acquire, Ordering::Relaxed)
+                    .is_ok()
+            {
+                return state;
+            }
+            hint::spin_loop();
+        }
+    }
+
+    fn unlock(&self, state: u8) {
+        self.state.store(state, Ordering::Release);
+    }
+}
+
+fn probe(slots: &[Slot], address: usize) -> impl Iterator<Item = &Slot> {
+    let start = ((address >> 4) as u64).wrapping_mul(0x9e37_79b9_7f4a_7c15) >> 32;
+    (0..MAX_PROBES).map(move |offset| &slots[(start as usize + offset) % slots.len()])
+}
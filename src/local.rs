@@ -0,0 +1,91 @@
+use std::cell::Cell;
+use std::marker::PhantomData;
+
+use crate::count::Counter;
+use crate::fault::Injector;
+use crate::region::RegionId;
+
+thread_local! {
+    static LOCAL: Local = const {
+        Local {
+            suppressed: Cell::new(0),
+            busy: Cell::new(false),
+            logging: Cell::new(None),
+            thread_name_match: Cell::new((0, false)),
+            region: Cell::new(None),
+            counter: Counter::new(),
+            forbidden: Cell::new(false),
+            injector: Cell::new(None),
+        }
+    };
+}
+
+/// Per-thread allocator state. This has no destructor, so it is accessible for the whole life of
+/// the thread and accessing it never allocates.
+pub(crate) struct Local {
+    /// The number of live `SuppressGuard`s on this thread.
+    pub suppressed: Cell<usize>,
+    /// Whether this thread is inside the allocator's own bookkeeping.
+    pub busy: Cell<bool>,
+    /// Overrides the global logging flag for this thread, if set.
+    pub logging: Cell<Option<bool>>,
+    /// The generation of the thread name filter last checked and whether this thread matched it.
+    pub thread_name_match: Cell<(u64, bool)>,
+    /// The innermost region entered on this thread.
+    pub region: Cell<Option<RegionId>>,
+    /// Allocator calls counted by `count_allocations` on this thread.
+    pub counter: Counter,
+    /// Whether this thread is inside a no-allocation zone.
+    pub forbidden: Cell<bool>,
+    /// The failure policy set for this thread by a `FailGuard`.
+    pub injector: Cell<Option<Injector>>,
+}
+
+/// Disables logging on the current thread until it is dropped.
+///
+/// Guards nest: logging resumes once every guard on the thread has been dropped. Allocations are
+/// still counted and tracked while logging is suppressed.
+#[must_use = "logging is only suppressed until the guard is dropped"]
+pub struct SuppressGuard {
+    _not_send: PhantomData<*const ()>,
+}
+
+pub(crate) fn with<F, R>(f: F) -> R
+where
+    F: FnOnce(&Local) -> R,
+{
+    LOCAL.with(f)
+}
+
+pub(crate) fn suppressed() -> bool {
+    with(|local| local.suppressed.get() != 0)
+}
+
+/// Run `f` unless this thread is already inside the allocator's own bookkeeping, so that
+/// allocations made while capturing stacks or updating tables are not themselves recorded.
+pub(crate) fn bookkeeping<F, R>(f: F) -> Option<R>
+where
+    F: FnOnce() -> R,
+{
+    if with(|local| local.busy.replace(true)) {
+        return None;
+    }
+    let result = f();
+    with(|local| local.busy.set(false));
+    Some(result)
+}
+
+impl SuppressGuard {
+    pub(crate) fn new() -> Self {
+        with(|local| local.suppressed.set(local.suppressed.get() + 1));
+        SuppressGuard {
+            _not_send: PhantomData,
+        }
+    }
+}
+
+impl Drop for SuppressGuard {
+    fn drop(&mut self) {
+        with(|local| local.suppressed.set(local.suppressed.get() - 1));
+    }
+}
@@ -0,0 +1,88 @@
+use std::fs::{self, File};
+use std::io;
+use std::path::{Path, PathBuf};
+
+use object::read::ReadCache;
+use object::Object;
+
+/// An executable mapping of a binary or shared library into a process's address space.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Module {
+    /// The path of the mapped file.
+    pub path: PathBuf,
+    /// The first address of the mapping.
+    pub start: usize,
+    /// The address after the end of the mapping.
+    pub end: usize,
+    /// The offset in the file of the start of the mapping.
+    pub offset: u64,
+    /// The GNU build id of the file, or empty if it has none.
+    pub build_id: Vec<u8>,
+}
+
+impl Module {
+    /// Find the executable mappings of the current process in `/proc/self/maps`, and read the
+    /// build id of each mapped file from its notes.
+    pub fn current() -> io::Result<Vec<Module>> {
+        let maps = fs::read_to_string("/proc/self/maps")?;
+        let mut modules: Vec<Module> = Vec::new();
+        for line in maps.lines() {
+            let mut fields = line.split_whitespace();
+            let (range, perms, offset) = match (fields.next(), fields.next(), fields.next()) {
+                (Some(range), Some(perms), Some(offset)) => (range, perms, offset),
+                _ => continue,
+            };
+            // Skip the device and inode.
+            let path = fields.nth(2).unwrap_or("");
+            if !perms.contains('x') || !path.starts_with('/') {
+                continue;
+            }
+            let (start, end) = match range.split_once('-') {
+                Some((start, end)) => (start, end),
+                None => continue,
+            };
+            let (start, end, offset) = match (
+                usize::from_str_radix(start, 16),
+                usize::from_str_radix(end, 16),
+                u64::from_str_radix(offset, 16),
+            ) {
+                (Ok(start), Ok(end), Ok(offset)) => (start, end, offset),
+                _ => continue,
+            };
+            let path = PathBuf::from(path);
+            let build_id = match modules.iter().find(|module| module.path == path) {
+                Some(module) => module.build_id.clone(),
+                None => build_id(&path).unwrap_or_default(),
+            };
+            modules.push(Module {
+                path,
+                start,
+                end,
+                offset,
+                build_id,
+            });
+        }
+        Ok(modules)
+    }
+
+    pub fn contains(&self, address: usize) -> bool {
+        self.start <= address && address < self.end
+    }
+
+    /// The offset in the file of the byte mapped at `address`.
+    pub fn file_offset(&self, address: usize) -> u64 {
+        (address - self.start) as u64 + self.offset
+    }
+}
+
+/// Read the GNU build id from the notes of the ELF file at `path`.
+pub(crate) fn build_id(path: &Path) -> io::Result<Vec<u8>> {
+    let cache = ReadCache::new(File::open(path)?);
+    let file = object::File::parse(&cache).map_err(invalid_data)?;
+    let build_id = file.build_id().map_err(invalid_data)?;
+    Ok(build_id.map(<[u8]>::to_vec).unwrap_or_default())
+}
+
+pub(crate) fn invalid_data(err: object::Error) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, err)
+}
@@ -0,0 +1,148 @@
+use std::alloc::{GlobalAlloc, Layout};
+use std::cell::UnsafeCell;
+use std::mem::MaybeUninit;
+use std::slice;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Mutex, MutexGuard};
+
+use crate::live::Record;
+use crate::table::Table;
+
+/// The maximum number of freed blocks held in quarantine at once. Once it is full, the oldest
+/// block is released whenever another is freed.
+pub const MAX_QUARANTINED: usize = 1 << 16;
+
+const POISON: u8 = 0xdd;
+
+/// A freed block waiting in quarantine to be returned to the inner allocator.
+#[derive(Clone, Copy)]
+pub(crate) struct Quarantined {
+    pub ptr: *mut u8,
+    /// The layout the block was freed with.
+    pub layout: Layout,
+    /// The layout the block was allocated with, if it has red zones.
+    pub red_zone: Option<Layout>,
+    /// What was recorded about the block while it was live, if it was tracked.
+    pub record: Option<Record>,
+}
+
+/// Writes found in the poison of a quarantined block.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) struct UseAfterFree {
+    /// The offset of the first byte overwritten.
+    pub offset: usize,
+    /// The number of bytes overwritten.
+    pub bytes: usize,
+}
+
+/// A bounded FIFO of freed blocks, limited by their total size.
+pub(crate) struct Quarantine {
+    max_bytes: AtomicUsize,
+    slots: Table<Slot>,
+    ring: Mutex<Ring>,
+}
+
+struct Slot(UnsafeCell<MaybeUninit<Quarantined>>);
+
+// Slots are only accessed while holding the ring's lock.
+unsafe impl Sync for Slot {}
+
+struct Ring {
+    /// The index of the oldest block.
+    head: usize,
+    len: usize,
+    bytes: usize,
+}
+
+impl Quarantine {
+    pub const fn new() -> Self {
+        Quarantine {
+            max_bytes: AtomicUsize::new(0),
+            slots: Table::new(MAX_QUARANTINED),
+            ring: Mutex::new(Ring {
+                head: 0,
+                len: 0,
+                bytes: 0,
+            }),
+        }
+    }
+
+    pub fn init<A>(&self, allocator: &A)
+    where
+        A: GlobalAlloc,
+    {
+        // Uninitialized slots may be all zero.
+        unsafe { self.slots.get_or_init(allocator) };
+    }
+
+    pub fn max_bytes(&self) -> usize {
+        self.max_bytes.load(Ordering::Relaxed)
+    }
+
+    pub fn set_max_bytes(&self, max_bytes: usize) {
+        self.max_bytes.store(max_bytes, Ordering::Relaxed)
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.max_bytes() != 0
+    }
+
+    fn ring(&self) -> MutexGuard<'_, Ring> {
+        self.ring.lock().unwrap_or_else(|err| err.into_inner())
+    }
+
+    /// The total size of the blocks in quarantine.
+    pub fn bytes(&self) -> usize {
+        self.ring().bytes
+    }
+
+    /// Add a freed block to the back of the quarantine, or give it back if the quarantine is full.
+    pub fn push(&self, block: Quarantined) -> Result<(), Quarantined> {
+        let slots = match self.slots.get() {
+            Some(slots) => slots,
+            None => return Err(block),
+        };
+        let mut ring = self.ring();
+        if ring.len == slots.len() {
+            return Err(block);
+        }
+        let index = (ring.head + ring.len) % slots.len();
+        unsafe { (*slots[index].0.get()).write(block) };
+        ring.len += 1;
+        ring.bytes += block.layout.size();
+        Ok(())
+    }
+
+    /// Take the oldest block out of quarantine. Unless `all` is set, a block is only taken while
+    /// the quarantine is over its size limit.
+    pub fn pop(&self, all: bool) -> Option<Quarantined> {
+        let slots = self.slots.get()?;
+        let mut ring = self.ring();
+        if ring.len == 0 || (!all && ring.bytes <= self.max_bytes()) {
+            return None;
+        }
+        let block = unsafe { (*slots[ring.head].0.get()).assume_init() };
+        ring.head = (ring.head + 1) % slots.len();
+        ring.len -= 1;
+        ring.bytes -= block.layout.size();
+        Some(block)
+    }
+}
+
+/// Fill a freed block with the poison pattern.
+pub(crate) unsafe fn poison(ptr: *mut u8, size: usize) {
+    ptr.write_bytes(POISON, size)
+}
+
+/// Check that a block filled by [`poison`] has not been written to since.
+pub(crate) unsafe fn check(ptr: *const u8, size: usize) -> Option<UseAfterFree> {
+    let bytes = slice::from_raw_parts(ptr, size);
+    let offset = bytes.iter().position(|&byte| byte != POISON)?;
+    Some(UseAfterFree {
+        offset,
+        bytes: bytes[offset..]
+            .iter()
+            .filter(|&&byte| byte != POISON)
+            .count(),
+    })
+}
@@ -0,0 +1,85 @@
+use std::alloc::{GlobalAlloc, Layout};
+use std::cmp;
+use std::ptr;
+use std::slice;
+
+/// The number of canary bytes placed on each side of a block. The red zone before a block is
+/// widened to the block's alignment if that is larger.
+pub const RED_ZONE: usize = 16;
+
+const CANARY: u8 = 0xfd;
+
+/// Damage found in the red zones of a block.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) struct Corruption {
+    /// The number of bytes overwritten immediately before the block.
+    pub underflow: usize,
+    /// The number of bytes overwritten immediately after the block.
+    pub overflow: usize,
+}
+
+fn front(align: usize) -> usize {
+    cmp::max(RED_ZONE, align)
+}
+
+/// The layout of the block allocated from the inner allocator for a block with `layout`.
+fn outer(layout: Layout) -> Option<Layout> {
+    let size = front(layout.align())
+        .checked_add(layout.size())?
+        .checked_add(RED_ZONE)?;
+    Layout::from_size_align(size, layout.align()).ok()
+}
+
+/// Allocate a block surrounded by red zones from `allocator`, returning a pointer to the block.
+pub(crate) unsafe fn alloc<A>(allocator: &A, layout: Layout, zeroed: bool) -> *mut u8
+where
+    A: GlobalAlloc,
+{
+    let outer = match outer(layout) {
+        Some(outer) => outer,
+        None => return ptr::null_mut(),
+    };
+    let base = if zeroed {
+        allocator.alloc_zeroed(outer)
+    } else {
+        allocator.alloc(outer)
+    };
+    if base.is_null() {
+        return base;
+    }
+    let front = front(layout.align());
+    ptr::write_bytes(base, CANARY, front);
+    ptr::write_bytes(base.add(front + layout.size()), CANARY, RED_ZONE);
+    base.add(front)
+}
+
+/// Free a block allocated by [`alloc`] with the same layout.
+pub(crate) unsafe fn dealloc<A>(allocator: &A, ptr: *mut u8, layout: Layout)
+where
+    A: GlobalAlloc,
+{
+    let outer = outer(layout).unwrap();
+    allocator.dealloc(ptr.sub(front(layout.align())), outer)
+}
+
+/// Check the red zones of a block allocated by [`alloc`] with the same layout.
+pub(crate) unsafe fn check(ptr: *const u8, layout: Layout) -> Option<Corruption> {
+    let front = front(layout.align());
+    let before = slice::from_raw_parts(ptr.sub(front), front);
+    let after = slice::from_raw_parts(ptr.add(layout.size()), RED_ZONE);
+    let corruption = Corruption {
+        underflow: before
+            .iter()
+            .position(|&byte| byte != CANARY)
+            .map_or(0, |index| front - index),
+        overflow: after
+            .iter()
+            .rposition(|&byte| byte != CANARY)
+            .map_or(0, |index| index + 1),
+    };
+    if corruption.underflow == 0 && corruption.overflow == 0 {
+        None
+    } else {
+        Some(corruption)
+    }
+}
@@ -0,0 +1,179 @@
+use std::fmt;
+use std::marker::PhantomData;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Mutex, MutexGuard};
+
+use crate::local;
+
+/// The maximum number of distinct region names. Regions created after this are ignored.
+pub const MAX_REGIONS: usize = 256;
+
+/// A snapshot of the counters for a named region.
+///
+/// Counters accumulate over every time a region with this name was entered, on any thread.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RegionStats {
+    pub name: &'static str,
+    /// The number of blocks allocated or reallocated in the region.
+    pub allocations: u64,
+    /// The total size of all blocks allocated or reallocated in the region.
+    pub bytes: u64,
+    /// The total size of blocks allocated in the region that have not been freed.
+    pub live_bytes: usize,
+    /// The highest value of `live_bytes`.
+    pub peak_live_bytes: usize,
+}
+
+/// Attributes allocations on the current thread to a region until it is dropped.
+///
+/// Regions nest: an allocation is attributed only to the innermost region, and dropping a guard
+/// makes the enclosing region current again.
+#[must_use = "allocations are only attributed to the region until the guard is dropped"]
+pub struct RegionGuard<'a> {
+    regions: &'a Regions,
+    id: Option<RegionId>,
+    previous: Option<RegionId>,
+    _not_send: PhantomData<*const ()>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) struct RegionId(u16);
+
+pub(crate) struct Regions {
+    names: Mutex<[Option<&'static str>; MAX_REGIONS]>,
+    counters: [Counters; MAX_REGIONS],
+}
+
+struct Counters {
+    allocations: AtomicU64,
+    bytes: AtomicU64,
+    live_bytes: AtomicUsize,
+    peak_live_bytes: AtomicUsize,
+}
+
+impl Regions {
+    pub const fn new() -> Self {
+        Regions {
+            names: Mutex::new([None; MAX_REGIONS]),
+            counters: [const { Counters::new() }; MAX_REGIONS],
+        }
+    }
+
+    fn names(&self) -> MutexGuard<'_, [Option<&'static str>; MAX_REGIONS]> {
+        self.names.lock().unwrap_or_else(|err| err.into_inner())
+    }
+
+    /// Find the region with this name, registering it if necessary.
+    fn id(&self, name: &'static str) -> Option<RegionId> {
+        let mut names = self.names();
+        let index = match names.iter().position(|&slot| slot == Some(name)) {
+            Some(index) => index,
+            None => {
+                let index = names.iter().position(Option::is_none)?;
+                names[index] = Some(name);
+                index
+            }
+        };
+        Some(RegionId(index as u16))
+    }
+
+    pub fn enter(&self, name: &'static str) -> RegionGuard<'_> {
+        let id = self.id(name);
+        let previous = local::with(|local| match id {
+            Some(id) => local.region.replace(Some(id)),
+            None => local.region.get(),
+        });
+        RegionGuard {
+            regions: self,
+            id,
+            previous,
+            _not_send: PhantomData,
+        }
+    }
+
+    pub fn alloc(&self, id: RegionId, size: usize) {
+        let counters = &self.counters[id.0 as usize];
+        counters.allocations.fetch_add(1, Ordering::Relaxed);
+        counters.bytes.fetch_add(size as u64, Ordering::Relaxed);
+        let live = counters.live_bytes.fetch_add(size, Ordering::Relaxed) + size;
+        counters.peak_live_bytes.fetch_max(live, Ordering::Relaxed);
+    }
+
+    pub fn dealloc(&self, id: RegionId, size: usize) {
+        self.counters[id.0 as usize]
+            .live_bytes
+            .fetch_sub(size, Ordering::Relaxed);
+    }
+
+    fn stats(&self, id: RegionId, name: &'static str) -> RegionStats {
+        let counters = &self.counters[id.0 as usize];
+        RegionStats {
+            name,
+            allocations: counters.allocations.load(Ordering::Relaxed),
+            bytes: counters.bytes.load(Ordering::Relaxed),
+            live_bytes: counters.live_bytes.load(Ordering::Relaxed),
+            peak_live_bytes: counters.peak_live_bytes.load(Ordering::Relaxed),
+        }
+    }
+
+    pub fn find(&self, name: &str) -> Option<RegionStats> {
+        let names = self.names();
+        let index = names.iter().position(|&slot| slot == Some(name))?;
+        Some(self.stats(RegionId(index as u16), names[index]?))
+    }
+
+    pub fn all(&self) -> Vec<RegionStats> {
+        let names = *self.names();
+        names
+            .iter()
+            .enumerate()
+            .filter_map(|(index, &name)| Some(self.stats(RegionId(index as u16), name?)))
+            .collect()
+    }
+}
+
+impl Counters {
+    const fn new() -> Self {
+        Counters {
+            allocations: AtomicU64::new(0),
+            bytes: AtomicU64::new(0),
+            live_bytes: AtomicUsize::new(0),
+            peak_live_bytes: AtomicUsize::new(0),
+        }
+    }
+}
+
+/// The region allocations on the current thread are attributed to.
+pub(crate) fn current() -> Option<RegionId> {
+    local::with(|local| local.region.get())
+}
+
+impl RegionGuard<'_> {
+    /// Get the counters for this region, or `None` if there were too many regions to register it.
+    pub fn stats(&self) -> Option<RegionStats> {
+        let id = self.id?;
+        let name = self.regions.names()[id.0 as usize]?;
+        Some(self.regions.stats(id, name))
+    }
+
+    /// Leave the region, returning its counters.
+    pub fn end(self) -> Option<RegionStats> {
+        self.stats()
+    }
+}
+
+impl Drop for RegionGuard<'_> {
+    fn drop(&mut self) {
+        local::with(|local| local.region.set(self.previous));
+    }
+}
+
+impl fmt::Display for RegionStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "region {}: {} allocations, {} bytes, {} bytes live, peak {} bytes live",
+            self.name, self.allocations, self.bytes, self.live_bytes, self.peak_live_bytes
+        )
+    }
+}
@@ -0,0 +1,131 @@
+use std::cell::Cell;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::event;
+
+thread_local! {
+    static STATE: State = const {
+        State {
+            remaining: Cell::new(0),
+            rng: Cell::new(0),
+        }
+    };
+}
+
+/// Decides which allocations to sample, as a Poisson process over the bytes allocated on each
+/// thread.
+pub(crate) struct Sampler {
+    interval: AtomicUsize,
+}
+
+struct State {
+    /// Bytes left to allocate on this thread before the next sample.
+    remaining: Cell<i64>,
+    /// xorshift state, or zero if not yet seeded.
+    rng: Cell<u64>,
+}
+
+impl Sampler {
+    pub const fn new() -> Self {
+        Sampler {
+            interval: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn interval(&self) -> usize {
+        self.interval.load(Ordering::Relaxed)
+    }
+
+    pub fn set_interval(&self, interval: usize) {
+        self.interval.store(interval, Ordering::Relaxed)
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.interval() != 0
+    }
+
+    /// Decide whether to sample an allocation of `size` bytes. If it is sampled, returns the
+    /// number of allocations of that size it stands for; otherwise returns `None`.
+    pub fn sample(&self, size: usize) -> Option<f64> {
+        let interval = self.interval();
+        if interval == 0 {
+            return Some(1.0);
+        }
+        if size == 0 {
+            return None;
+        }
+
+        STATE
+            .try_with(|state| {
+                if state.rng.get() == 0 {
+                    state.rng.set(seed());
+                    state.remaining.set(state.next_interval(interval));
+                }
+                let remaining = state.remaining.get() - size as i64;
+                if remaining > 0 {
+                    state.remaining.set(remaining);
+                    return None;
+                }
+                state.remaining.set(state.next_interval(interval));
+                // The probability that a sample point falls within `size` bytes.
+                let probability = 1.0 - (-(size as f64) / interval as f64).exp();
+                Some(1.0 / probability)
+            })
+            .ok()
+            .flatten()
+    }
+}
+
+impl State {
+    /// Draw the distance to the next sample from an exponential distribution with mean
+    /// `interval`.
+    fn next_interval(&self, interval: usize) -> i64 {
+        let mut x = self.rng.get();
+        x ^= x << 13;
+        x ^= x >> 7;
+        x ^= x << 17;
+        self.rng.set(x);
+        // Uniform in (0, 1].
+        let uniform = ((x >> 11) as f64 + 1.0) / (1u64 << 53) as f64;
+        (-uniform.ln() * interval as f64) as i64 + 1
+    }
+}
+
+fn seed() -> u64 {
+    let seed = event::thread_id().wrapping_mul(0x9e37_79b9_7f4a_7c15) ^ event::timestamp();
+    seed | 1
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unbiased_weights() {
+        let sampler = Sampler::new();
+        sampler.set_interval(4096);
+        let sizes = [16, 100, 1000, 5000, 40000];
+        let mut total = 0.0;
+        let mut estimate = 0.0;
+        for _ in 0..20000 {
+            for &size in &sizes {
+                total += size as f64;
+                if let Some(weight) = sampler.sample(size) {
+                    assert!(weight >= 1.0);
+                    estimate += size as f64 * weight;
+                }
+            }
+        }
+        let error = (estimate - total).abs() / total;
+        assert!(error < 0.02, "estimated {} bytes of {}", estimate, total);
+    }
+
+    #[test]
+    fn disabled() {
+        let sampler = Sampler::new();
+        assert!(!sampler.is_enabled());
+        assert_eq!(sampler.sample(1), Some(1.0));
+        sampler.set_interval(4096);
+        assert_eq!(sampler.sample(0), None);
+    }
+}
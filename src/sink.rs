@@ -0,0 +1,105 @@
+use std::io::{self, Write};
+use std::sync::Mutex;
+
+use crate::AllocEvent;
+
+/// A destination for the events logged by a `LoggingAllocator`.
+///
+/// Sinks are called from inside the global allocator, so implementations must not allocate.
+/// Allocations made by a sink are not logged, but they still recurse into the allocator.
+pub trait Sink {
+    /// Record a single event.
+    fn log(&self, event: &AllocEvent);
+
+    /// Called when `count` events were lost before reaching this sink.
+    fn dropped(&self, count: u64) {
+        let _ = count;
+    }
+
+    /// Write out any buffered events.
+    fn flush(&self) {}
+}
+
+impl<S> Sink for &S
+where
+    S: Sink + ?Sized,
+{
+    fn log(&self, event: &AllocEvent) {
+        (**self).log(event)
+    }
+
+    fn dropped(&self, count: u64) {
+        (**self).dropped(count)
+    }
+
+    fn flush(&self) {
+        (**self).flush()
+    }
+}
+
+/// A sink that writes each event to standard error. This is the default sink.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct StderrSink;
+
+impl Sink for StderrSink {
+    fn log(&self, event: &AllocEvent) {
+        let _ = writeln!(io::stderr(), "{}", event);
+    }
+
+    fn dropped(&self, count: u64) {
+        let _ = writeln!(io::stderr(), "dropped {} events", count);
+    }
+}
+
+/// A sink that writes each event to a writer attached at runtime, such as a file or socket.
+///
+/// Events logged while no writer is attached are discarded.
+#[derive(Debug, Default)]
+pub struct WriteSink<W> {
+    writer: Mutex<Option<W>>,
+}
+
+impl<W> WriteSink<W> {
+    pub const fn new() -> Self {
+        WriteSink {
+            writer: Mutex::new(None),
+        }
+    }
+
+    /// Start writing events to `writer`, returning the previously attached writer.
+    pub fn attach(&self, writer: W) -> Option<W> {
+        self.lock().replace(writer)
+    }
+
+    /// Stop writing events, returning the attached writer.
+    pub fn detach(&self) -> Option<W> {
+        self.lock().take()
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Option<W>> {
+        self.writer.lock().unwrap_or_else(|err| err.into_inner())
+    }
+}
+
+impl<W> Sink for WriteSink<W>
+where
+    W: Write,
+{
+    fn log(&self, event: &AllocEvent) {
+        if let Some(writer) = self.lock().as_mut() {
+            let _ = writeln!(writer, "{}", event);
+        }
+    }
+
+    fn dropped(&self, count: u64) {
+        if let Some(writer) = self.lock().as_mut() {
+            let _ = writeln!(writer, "dropped {} events", count);
+        }
+    }
+
+    fn flush(&self) {
+        if let Some(writer) = self.lock().as_mut() {
+            let _ = writer.flush();
+        }
+    }
+}
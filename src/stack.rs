@@ -0,0 +1,563 @@
+use std::cell::UnsafeCell;
+use std::ffi::c_void;
+use std::fmt;
+use std::hint;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU8, AtomicUsize, Ordering};
+
+/// The maximum number of frames recorded for a single stack.
+pub const MAX_FRAMES: usize = 32;
+
+/// The number of distinct stacks that can be interned. Stacks captured after the table is full
+/// are discarded.
+pub const STACK_CAPACITY: usize = 1 << 14;
+
+const EMPTY: u8 = 0;
+const WRITING: u8 = 1;
+const READY: u8 = 2;
+
+static TABLE: StackTable = StackTable {
+    entries: [const { Entry::new() }; STACK_CAPACITY],
+};
+
+static UNWINDER: AtomicU8 = AtomicU8::new(Unwinder::Backtrace as u8);
+
+static MAX_DEPTH: AtomicUsize = AtomicUsize::new(MAX_FRAMES);
+
+/// How stacks are captured.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Unwinder {
+    /// Unwind with the platform unwinder and the unwind tables in the binary. This works for any
+    /// code, but is slow.
+    Backtrace,
+    /// Follow the chain of saved frame pointers. This is much faster, but only finds the frames
+    /// of code compiled with frame pointers, such as with `-C force-frame-pointers=yes`, and stops
+    /// at the first frame without one. Only supported on Linux on x86-64 and AArch64; elsewhere
+    /// stacks are unwound with [`Unwinder::Backtrace`].
+    FramePointers,
+}
+
+/// A handle to a stack interned in the process-wide stack table.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct StackId(u32);
+
+/// Formats a list of return addresses as a symbolized backtrace.
+pub(crate) struct Symbolized<'a>(pub &'a [usize]);
+
+/// A symbol that a frame of a stack resolves to, one of several if calls were inlined.
+struct Line {
+    index: usize,
+    address: usize,
+    name: Option<String>,
+    location: Option<(PathBuf, u32)>,
+}
+
+struct StackTable {
+    entries: [Entry; STACK_CAPACITY],
+}
+
+struct Entry {
+    state: AtomicU8,
+    hash: UnsafeCell<u64>,
+    len: UnsafeCell<usize>,
+    frames: UnsafeCell<[usize; MAX_FRAMES]>,
+}
+
+// Entries are only written while in the WRITING state, by the thread that claimed them, and
+// only read after being published in the READY state.
+unsafe impl Sync for Entry {}
+
+impl StackId {
+    pub const fn from_raw(raw: u32) -> Self {
+        StackId(raw)
+    }
+
+    pub const fn as_raw(self) -> u32 {
+        self.0
+    }
+
+    /// The return addresses of the stack with this id, innermost first, or `None` if no stack
+    /// with this id has been interned in this process.
+    pub fn frames(self) -> Option<&'static [usize]> {
+        let entry = TABLE.entries.get(self.0 as usize)?;
+        if entry.state.load(Ordering::Acquire) == READY {
+            Some(unsafe { entry.frames() })
+        } else {
+            None
+        }
+    }
+}
+
+impl Entry {
+    const fn new() -> Self {
+        Entry {
+            state: AtomicU8::new(EMPTY),
+            hash: UnsafeCell::new(0),
+            len: UnsafeCell::new(0),
+            frames: UnsafeCell::new([0; MAX_FRAMES]),
+        }
+    }
+
+    /// # Safety
+    ///
+    /// The entry must be in the READY state.
+    unsafe fn frames(&self) -> &[usize] {
+        &(&*self.frames.get())[..*self.len.get()]
+    }
+}
+
+pub(crate) fn unwinder() -> Unwinder {
+    match UNWINDER.load(Ordering::Relaxed) {
+        0 => Unwinder::Backtrace,
+        _ => Unwinder::FramePointers,
+    }
+}
+
+pub(crate) fn set_unwinder(unwinder: Unwinder) {
+    UNWINDER.store(unwinder as u8, Ordering::Relaxed)
+}
+
+pub(crate) fn max_depth() -> usize {
+    MAX_DEPTH.load(Ordering::Relaxed)
+}
+
+pub(crate) fn set_max_depth(depth: usize) {
+    MAX_DEPTH.store(depth.min(MAX_FRAMES), Ordering::Relaxed)
+}
+
+/// An address in the current stack frame, for trimming the frames below it from a capture.
+#[inline(always)]
+pub(crate) fn here() -> usize {
+    let marker = 0u8;
+    hint::black_box(&marker) as *const u8 as usize
+}
+
+/// Capture the current stack without allocating and intern it in the stack table. Frames with a
+/// stack pointer below `caller`, an address in the frame of the allocator's entry point, are the
+/// allocator's own and are skipped; zero skips none.
+pub(crate) fn capture(caller: usize) -> Option<StackId> {
+    let mut frames = [0; MAX_FRAMES];
+    let len = match unwinder() {
+        Unwinder::FramePointers => walk_frame_pointers(&mut frames, caller),
+        Unwinder::Backtrace => None,
+    };
+    let len = len.unwrap_or_else(|| unwind(&mut frames, caller));
+    intern(&frames[..len])
+}
+
+fn unwind(frames: &mut [usize], caller: usize) -> usize {
+    let mut len = 0;
+    let mut skipping = caller != 0;
+    unsafe {
+        backtrace::trace_unsynchronized(|frame| {
+            if frame.ip().is_null() || len == frames.len() {
+                return false;
+            }
+            let sp = frame.sp() as usize;
+            // The stack grows down, so the allocator's frames are below its entry point.
+            if skipping && sp != 0 && sp <= caller {
+                return true;
+            }
+            skipping = false;
+            frames[len] = frame.ip() as usize;
+            len += 1;
+            len < frames.len()
+        });
+    }
+    len
+}
+
+/// Walk the chain of saved frame pointers, which is only followed while it stays within the
+/// current thread's stack, so a broken chain ends the walk rather than faulting. Returns `None`
+/// if frame pointers cannot be walked on this platform.
+#[cfg(all(
+    target_os = "linux",
+    any(target_arch = "x86_64", target_arch = "aarch64")
+))]
+#[inline(never)]
+fn walk_frame_pointers(frames: &mut [usize], caller: usize) -> Option<usize> {
+    use std::arch::asm;
+
+    let bounds = stack_bounds()?;
+    let fp: usize;
+    unsafe {
+        #[cfg(target_arch = "x86_64")]
+        asm!("mov {}, rbp", out(reg) fp, options(nomem, nostack, preserves_flags));
+        #[cfg(target_arch = "aarch64")]
+        asm!("mov {}, x29", out(reg) fp, options(nomem, nostack, preserves_flags));
+    }
+    Some(walk_frame_records(fp, bounds, frames, caller))
+}
+
+/// Follow the chain of frame records starting at `fp` while it stays within `low..high`.
+#[cfg(all(
+    target_os = "linux",
+    any(target_arch = "x86_64", target_arch = "aarch64")
+))]
+fn walk_frame_records(
+    mut fp: usize,
+    (low, high): (usize, usize),
+    frames: &mut [usize],
+    caller: usize,
+) -> usize {
+    use std::mem;
+
+    let mut len = 0;
+    // Each frame record holds the caller's frame pointer followed by the return address.
+    while len < frames.len()
+        && fp >= low
+        && fp <= high - 2 * mem::size_of::<usize>()
+        && fp.is_multiple_of(mem::align_of::<usize>())
+    {
+        let record = fp as *const usize;
+        let (next, return_address) = unsafe { (*record, *record.add(1)) };
+        if return_address == 0 {
+            break;
+        }
+        // A record below `caller` belongs to one of the allocator's frames, and so does the
+        // return address in it.
+        if fp >= caller {
+            frames[len] = return_address;
+            len += 1;
+        }
+        // The stack grows down, so callers' frames are at higher addresses.
+        if next <= fp {
+            break;
+        }
+        fp = next;
+    }
+    len
+}
+
+#[cfg(not(all(
+    target_os = "linux",
+    any(target_arch = "x86_64", target_arch = "aarch64")
+)))]
+fn walk_frame_pointers(_: &mut [usize], _: usize) -> Option<usize> {
+    None
+}
+
+/// The bounds of the current thread's stack, looked up once per thread.
+#[cfg(target_os = "linux")]
+fn stack_bounds() -> Option<(usize, usize)> {
+    use std::cell::Cell;
+
+    thread_local! {
+        /// The lowest and highest addresses of this thread's stack, or zero if not yet known.
+        static STACK_BOUNDS: Cell<(usize, usize)> = const { Cell::new((0, 0)) };
+    }
+
+    let bounds = STACK_BOUNDS.try_with(Cell::get).ok()?;
+    if bounds != (0, 0) {
+        return Some(bounds);
+    }
+    // glibc reads /proc/self/maps for the main thread, which uses its own malloc rather than the
+    // global allocator.
+    let bounds = unsafe {
+        let mut attr = std::mem::zeroed();
+        if libc::pthread_getattr_np(libc::pthread_self(), &mut attr) != 0 {
+            return None;
+        }
+        let mut addr = std::ptr::null_mut();
+        let mut size = 0;
+        let result = libc::pthread_attr_getstack(&attr, &mut addr, &mut size);
+        libc::pthread_attr_destroy(&mut attr);
+        if result != 0 {
+            return None;
+        }
+        (addr as usize, addr as usize + size)
+    };
+    let _ = STACK_BOUNDS.try_with(|cell| cell.set(bounds));
+    Some(bounds)
+}
+
+/// Intern a stack, returning the id of an identical stack if one was already interned.
+pub(crate) fn intern(frames: &[usize]) -> Option<StackId> {
+    let frames = &frames[..frames.len().min(MAX_FRAMES)];
+    let hash = hash(frames);
+    let mut index = hash as usize % STACK_CAPACITY;
+    for _ in 0..STACK_CAPACITY {
+        let entry = &TABLE.entries[index];
+        match entry.state.load(Ordering::Acquire) {
+            EMPTY => {
+                if entry
+                    .state
+                    .compare_exchange(EMPTY, WRITING, Ordering::Acquire, Ordering::Relaxed)
+                    .is_ok()
+                {
+                    unsafe {
+                        *entry.hash.get() = hash;
+                        *entry.len.get() = frames.len();
+                        (&mut *entry.frames.get())[..frames.len()].copy_from_slice(frames);
+                    }
+                    entry.state.store(READY, Ordering::Release);
+                    return Some(StackId(index as u32));
+                }
+                continue;
+            }
+            WRITING => {
+                hint::spin_loop();
+                continue;
+            }
+            _ => unsafe {
+                if *entry.hash.get() == hash && entry.frames() == frames {
+                    return Some(StackId(index as u32));
+                }
+            },
+        }
+        index = (index + 1) % STACK_CAPACITY;
+    }
+    None
+}
+
+fn hash(frames: &[usize]) -> u64 {
+    // FNV-1a
+    let mut hash = 0xcbf2_9ce4_8422_2325u64;
+    for &frame in frames {
+        for byte in (frame as u64).to_le_bytes() {
+            hash ^= u64::from(byte);
+            hash = hash.wrapping_mul(0x0100_0000_01b3);
+        }
+    }
+    hash
+}
+
+impl fmt::Display for StackId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.frames() {
+            Some(frames) => Symbolized(frames).fmt(f),
+            None => write!(f, "<unknown stack {}>", self.0),
+        }
+    }
+}
+
+impl fmt::Display for Symbolized<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut lines = Vec::new();
+        for (index, &frame) in self.0.iter().enumerate() {
+            let mut resolved = false;
+            backtrace::resolve(frame as *mut c_void, |symbol| {
+                lines.push(Line {
+                    index,
+                    address: frame,
+                    name: symbol.name().map(|name| format!("{:#}", name)),
+                    location: symbol
+                        .filename()
+                        .map(Path::to_path_buf)
+                        .zip(symbol.lineno()),
+                });
+                resolved = true;
+            });
+            if !resolved {
+                lines.push(Line {
+                    index,
+                    address: frame,
+                    name: None,
+                    location: None,
+                });
+            }
+        }
+        trim(&mut lines, max_depth());
+        for line in &lines {
+            match &line.name {
+                Some(name) => writeln!(f, "{:4}: {}", line.index, name)?,
+                None => writeln!(f, "{:4}: {:#x}", line.index, line.address)?,
+            }
+            if let Some((file, number)) = &line.location {
+                writeln!(f, "             at {}:{}", file.display(), number)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Leave out the symbols of the allocation functions in `alloc` that led to the allocator, unless
+/// that is all there is, and then every frame more than `depth` frames out from the first one
+/// left.
+fn trim(lines: &mut Vec<Line>, depth: usize) {
+    let internal = |line: &Line| line.name.as_deref().is_some_and(is_internal);
+    if let Some(skip) = lines.iter().position(|line| !internal(line)) {
+        lines.drain(..skip);
+    }
+    if let Some(first) = lines.first().map(|line| line.index) {
+        lines.retain(|line| line.index - first < depth);
+    }
+}
+
+/// Whether a demangled function name is one of the functions in `alloc` and `core::alloc` that
+/// allocate on behalf of collections, such as those of `RawVec`, the allocator shims between them
+/// and the global allocator, or the drop glue of a type in `alloc`.
+pub(crate) fn is_internal(name: &str) -> bool {
+    const PREFIXES: [&str; 6] = [
+        "__rust_",
+        "__rustc::",
+        "alloc::",
+        "<alloc::",
+        "core::alloc::",
+        "<core::alloc::",
+    ];
+    // Implementations of traits in `alloc` for other types, such as `SpecFromElem` for `u8`.
+    const TRAITS: [&str; 2] = [" as alloc::", " as core::alloc::"];
+    const DROP_GLUE: [&str; 2] = [
+        "core::ptr::drop_in_place<alloc::",
+        "core::ptr::drop_in_place::<alloc::",
+    ];
+    PREFIXES
+        .iter()
+        .chain(&DROP_GLUE)
+        .any(|prefix| name.starts_with(prefix))
+        || (name.starts_with('<') && TRAITS.iter().any(|name_of| name.contains(name_of)))
+}
+
+/// A stack that is captured the first time it is needed, so one allocator call never captures
+/// more than once.
+pub(crate) struct LazyStack {
+    stack: Option<Option<StackId>>,
+    caller: usize,
+}
+
+impl LazyStack {
+    /// `caller` is an address in the frame of the allocator's entry point, from [`here`].
+    pub const fn new(caller: usize) -> Self {
+        LazyStack {
+            stack: None,
+            caller,
+        }
+    }
+
+    pub fn caller(&self) -> usize {
+        self.caller
+    }
+
+    pub fn get(&mut self) -> Option<StackId> {
+        let caller = self.caller;
+        *self.stack.get_or_insert_with(|| capture(caller))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn internal_frames() {
+        for name in [
+            "__rustc::__rust_alloc",
+            "__rust_alloc_zeroed",
+            "alloc::alloc::alloc",
+            "alloc::raw_vec::RawVecInner<A>::try_allocate_in",
+            "alloc::vec::Vec<T,A>::push",
+            "<alloc::alloc::Global as core::alloc::Allocator>::allocate",
+            "core::alloc::layout::Layout::array",
+            "<u8 as alloc::vec::spec_from_elem::SpecFromElem>::from_elem",
+            "<T as alloc::string::ToString>::to_string",
+            "<logging_allocator::LoggingAllocator<A,S> as core::alloc::global::GlobalAlloc>::alloc",
+            "core::ptr::drop_in_place<alloc::vec::Vec<u8>>",
+            "core::ptr::drop_in_place::<alloc::sync::Arc<u8>>",
+        ] {
+            assert!(is_internal(name), "{}", name);
+        }
+    }
+
+    #[test]
+    fn user_frames() {
+        for name in [
+            "main",
+            "example::main",
+            "std::rt::lang_start::{{closure}}",
+            "<example::Foo as core::ops::drop::Drop>::drop",
+            "<example::Foo<alloc::vec::Vec<u8>> as example::Trait>::run",
+            "core::ptr::drop_in_place<example::Foo>",
+            "core::ops::function::FnOnce::call_once",
+        ] {
+            assert!(!is_internal(name), "{}", name);
+        }
+    }
+
+    #[cfg(all(
+        target_os = "linux",
+        any(target_arch = "x86_64", target_arch = "aarch64")
+    ))]
+    #[test]
+    fn frame_pointer_walk() {
+        let mut frames = [0; MAX_FRAMES];
+        let len = walk_frame_pointers(&mut frames, 0).unwrap();
+        assert!(len > 0);
+        // Every return address found is one the platform unwinder finds too, though frames
+        // without frame pointers are missed.
+        let mut unwound = [0; MAX_FRAMES];
+        let unwound_len = unwind(&mut unwound, 0);
+        let unwound = &unwound[..unwound_len];
+        assert!(frames[..len].iter().all(|frame| unwound.contains(frame)));
+    }
+
+    #[cfg(all(
+        target_os = "linux",
+        any(target_arch = "x86_64", target_arch = "aarch64")
+    ))]
+    #[test]
+    fn broken_frame_pointer_chain() {
+        let mut stack = [0usize; 8];
+        let base = stack.as_ptr() as usize;
+        let bounds = (base, base + std::mem::size_of_val(&stack));
+        let record = |index: usize| base + index * std::mem::size_of::<usize>();
+        // A chain that leaves the stack, as code without frame pointers leaves it.
+        stack[..4].copy_from_slice(&[record(2), 0x1000, 0x10, 0x2000]);
+        // A record that points to itself.
+        stack[4..6].copy_from_slice(&[record(4), 0x3000]);
+        // A record at the end of the stack.
+        stack[6..].copy_from_slice(&[usize::MAX, 0x4000]);
+
+        let mut frames = [0; MAX_FRAMES];
+        let mut walk = |fp, caller| {
+            let len = walk_frame_records(fp, bounds, &mut frames, caller);
+            frames[..len].to_vec()
+        };
+        assert_eq!(walk(record(0), 0), [0x1000, 0x2000]);
+        assert_eq!(walk(record(0), record(1)), [0x2000]);
+        assert_eq!(walk(record(4), 0), [0x3000]);
+        assert_eq!(walk(record(6), 0), [0x4000]);
+        assert_eq!(walk(record(0) + 1, 0), []);
+        assert_eq!(walk(0xdead_beef, 0), []);
+    }
+
+    #[test]
+    fn trim_internal_symbols() {
+        let line = |index, name: &str| Line {
+            index,
+            address: index,
+            name: Some(name.to_owned()),
+            location: None,
+        };
+        // The first frame is `alloc` inlined into the caller, which is kept.
+        let mut lines = vec![
+            line(0, "alloc::alloc::alloc"),
+            line(1, "alloc::raw_vec::RawVecInner<A>::try_allocate_in"),
+            line(1, "alloc::vec::Vec<T,A>::push"),
+            line(1, "example::push"),
+            line(2, "example::run"),
+            line(3, "example::main"),
+        ];
+        trim(&mut lines, 2);
+        let names = lines
+            .iter()
+            .map(|line| line.name.as_deref().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(names, ["example::push", "example::run"]);
+
+        let mut lines = vec![
+            line(0, "alloc::alloc::alloc"),
+            line(1, "alloc::vec::Vec<T,A>::push"),
+        ];
+        trim(&mut lines, MAX_FRAMES);
+        assert_eq!(lines.len(), 2);
+    }
+
+    #[test]
+    fn max_depth_is_clamped() {
+        let depth = max_depth();
+        set_max_depth(MAX_FRAMES + 1);
+        assert_eq!(max_depth(), MAX_FRAMES);
+        set_max_depth(depth);
+    }
+}
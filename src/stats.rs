@@ -0,0 +1,141 @@
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+
+/// A snapshot of the counters kept by a `LoggingAllocator`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Stats {
+    /// The number of successful calls to `alloc` and `alloc_zeroed`.
+    pub allocations: u64,
+    /// The number of calls to `dealloc`.
+    pub deallocations: u64,
+    /// The number of successful calls to `realloc`.
+    pub reallocations: u64,
+    /// The number of bytes currently allocated.
+    pub bytes_in_use: usize,
+    /// The highest value of `bytes_in_use` since the counters were last reset.
+    pub peak_bytes_in_use: usize,
+    /// The size of the largest block allocated since the counters were last reset.
+    pub largest_allocation: usize,
+}
+
+pub(crate) struct Counters {
+    allocations: AtomicU64,
+    deallocations: AtomicU64,
+    reallocations: AtomicU64,
+    bytes_in_use: AtomicUsize,
+    peak_bytes_in_use: AtomicUsize,
+    largest_allocation: AtomicUsize,
+}
+
+impl Counters {
+    pub const fn new() -> Self {
+        Counters {
+            allocations: AtomicU64::new(0),
+            deallocations: AtomicU64::new(0),
+            reallocations: AtomicU64::new(0),
+            bytes_in_use: AtomicUsize::new(0),
+            peak_bytes_in_use: AtomicUsize::new(0),
+            largest_allocation: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn alloc(&self, size: usize) {
+        self.allocations.fetch_add(1, Ordering::Relaxed);
+        self.grow(size);
+        self.largest_allocation.fetch_max(size, Ordering::Relaxed);
+    }
+
+    pub fn dealloc(&self, size: usize) {
+        self.deallocations.fetch_add(1, Ordering::Relaxed);
+        self.bytes_in_use.fetch_sub(size, Ordering::Relaxed);
+    }
+
+    pub fn realloc(&self, old_size: usize, new_size: usize) {
+        self.reallocations.fetch_add(1, Ordering::Relaxed);
+        if new_size > old_size {
+            self.grow(new_size - old_size);
+        } else {
+            self.bytes_in_use
+                .fetch_sub(old_size - new_size, Ordering::Relaxed);
+        }
+        self.largest_allocation
+            .fetch_max(new_size, Ordering::Relaxed);
+    }
+
+    pub fn bytes_in_use(&self) -> usize {
+        self.bytes_in_use.load(Ordering::Relaxed)
+    }
+
+    fn grow(&self, size: usize) {
+        let in_use = self.bytes_in_use.fetch_add(size, Ordering::Relaxed) + size;
+        self.peak_bytes_in_use.fetch_max(in_use, Ordering::Relaxed);
+    }
+
+    pub fn snapshot(&self) -> Stats {
+        Stats {
+            allocations: self.allocations.load(Ordering::Relaxed),
+            deallocations: self.deallocations.load(Ordering::Relaxed),
+            reallocations: self.reallocations.load(Ordering::Relaxed),
+            bytes_in_use: self.bytes_in_use.load(Ordering::Relaxed),
+            peak_bytes_in_use: self.peak_bytes_in_use.load(Ordering::Relaxed),
+            largest_allocation: self.largest_allocation.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Reset every counter except `bytes_in_use`, which always reflects the live heap.
+    pub fn reset(&self) {
+        self.allocations.store(0, Ordering::Relaxed);
+        self.deallocations.store(0, Ordering::Relaxed);
+        self.reallocations.store(0, Ordering::Relaxed);
+        self.largest_allocation.store(0, Ordering::Relaxed);
+        self.peak_bytes_in_use
+            .store(self.bytes_in_use.load(Ordering::Relaxed), Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bytes_in_use() {
+        let counters = Counters::new();
+        counters.alloc(100);
+        counters.alloc(50);
+        counters.realloc(100, 300);
+        counters.realloc(50, 20);
+        counters.dealloc(300);
+        let stats = counters.snapshot();
+        assert_eq!(stats.allocations, 2);
+        assert_eq!(stats.reallocations, 2);
+        assert_eq!(stats.deallocations, 1);
+        assert_eq!(stats.bytes_in_use, 20);
+        assert_eq!(stats.peak_bytes_in_use, 350);
+        assert_eq!(stats.largest_allocation, 300);
+    }
+
+    #[test]
+    fn reset() {
+        let counters = Counters::new();
+        counters.alloc(100);
+        counters.alloc(200);
+        counters.dealloc(200);
+        counters.reset();
+        assert_eq!(
+            counters.snapshot(),
+            Stats {
+                bytes_in_use: 100,
+                peak_bytes_in_use: 100,
+                ..Stats::default()
+            }
+        );
+
+        // Bytes allocated before the reset are still accounted for when they are freed.
+        counters.realloc(100, 150);
+        counters.alloc(10);
+        counters.dealloc(150);
+        let stats = counters.snapshot();
+        assert_eq!(stats.bytes_in_use, 10);
+        assert_eq!(stats.peak_bytes_in_use, 160);
+        assert_eq!(stats.largest_allocation, 150);
+    }
+}
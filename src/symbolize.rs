@@ -0,0 +1,282 @@
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use addr2line::Context;
+use gimli::{EndianArcSlice, RunTimeEndian};
+use object::{Object, ObjectSection, ObjectSegment};
+
+use crate::module::{self, Module};
+use crate::stack;
+
+/// A function and source location that an address resolved to.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Symbol {
+    /// The address that was resolved.
+    pub address: usize,
+    /// The demangled name of the function, if it was found.
+    pub name: Option<String>,
+    pub file: Option<PathBuf>,
+    pub line: Option<u32>,
+}
+
+/// Resolves addresses captured in a process to function names and source locations, using the
+/// binaries that were mapped into it and their debug info.
+///
+/// This can run after the fact and in another process, given the module map of the process that
+/// captured the addresses, as long as the binaries are unchanged. Each file is checked against the
+/// build id recorded in the module map, and debug info is also looked for in
+/// `/usr/lib/debug/.build-id`. The debug info of each file is loaded the first time an address in
+/// it is resolved and kept until the symbolizer is dropped, and every resolved address is cached.
+pub struct Symbolizer {
+    modules: Vec<Module>,
+    files: HashMap<PathBuf, Option<DebugInfo>>,
+    cache: HashMap<usize, Vec<Symbol>>,
+}
+
+/// What is loaded from a binary to resolve addresses in it.
+struct DebugInfo {
+    context: Option<Context<EndianArcSlice<RunTimeEndian>>>,
+    /// The address and name of each symbol, sorted by address, for code without debug info.
+    symbols: Vec<(u64, Box<str>)>,
+    /// The file offset, file size, and address of each segment.
+    segments: Vec<(u64, u64, u64)>,
+}
+
+impl Symbolizer {
+    /// Create a symbolizer for addresses captured in a process with these modules.
+    pub fn new(modules: Vec<Module>) -> Self {
+        Symbolizer {
+            modules,
+            files: HashMap::new(),
+            cache: HashMap::new(),
+        }
+    }
+
+    /// Create a symbolizer for addresses captured in the current process.
+    pub fn current() -> io::Result<Self> {
+        Ok(Symbolizer::new(Module::current()?))
+    }
+
+    pub fn modules(&self) -> &[Module] {
+        &self.modules
+    }
+
+    /// Resolve a return address. If it is in an inlined function, there is one symbol for the
+    /// inlined function and each function it was inlined into, innermost first. If it cannot be
+    /// resolved there are none.
+    pub fn symbolize(&mut self, address: usize) -> &[Symbol] {
+        if !self.cache.contains_key(&address) {
+            let symbols = self.resolve(address);
+            self.cache.insert(address, symbols);
+        }
+        &self.cache[&address]
+    }
+
+    /// Resolve every return address in a stack, innermost first. Addresses that cannot be
+    /// resolved are included as symbols without a name or location. The functions in `alloc`
+    /// that led to the allocator, such as those of `RawVec`, are left out of the start of the
+    /// stack unless that is all there is.
+    pub fn symbolize_stack(&mut self, frames: &[usize]) -> Vec<Symbol> {
+        let mut symbols = Vec::new();
+        for &address in frames {
+            match self.symbolize(address) {
+                [] => symbols.push(Symbol {
+                    address,
+                    name: None,
+                    file: None,
+                    line: None,
+                }),
+                resolved => symbols.extend_from_slice(resolved),
+            }
+        }
+        let internal = |symbol: &Symbol| symbol.name.as_deref().is_some_and(stack::is_internal);
+        if let Some(skip) = symbols.iter().position(|symbol| !internal(symbol)) {
+            symbols.drain(..skip);
+        }
+        symbols
+    }
+
+    fn resolve(&mut self, address: usize) -> Vec<Symbol> {
+        let module = match self.modules.iter().find(|module| module.contains(address)) {
+            Some(module) => module,
+            None => return Vec::new(),
+        };
+        let info = self
+            .files
+            .entry(module.path.clone())
+            .or_insert_with(|| DebugInfo::load(module).ok().flatten());
+        let info = match info {
+            Some(info) => info,
+            None => return Vec::new(),
+        };
+        // Look up the call instruction rather than the instruction after it, which may belong to
+        // a different line or function.
+        let offset = module.file_offset(address).saturating_sub(1);
+        match info.address(offset) {
+            Some(probe) => info.resolve(address, probe),
+            None => Vec::new(),
+        }
+    }
+}
+
+impl DebugInfo {
+    /// Load the binary mapped by `module`, or its separate debug info. Returns `None` if the
+    /// binary has changed since the module map was captured.
+    fn load(module: &Module) -> io::Result<Option<Self>> {
+        if !module.build_id.is_empty() && module::build_id(&module.path)? != module.build_id {
+            return Ok(None);
+        }
+        let data = fs::read(&module.path)?;
+        let file = object::File::parse(&*data).map_err(module::invalid_data)?;
+        let segments = file
+            .segments()
+            .map(|segment| {
+                let (offset, size) = segment.file_range();
+                (offset, size, segment.address())
+            })
+            .collect();
+        let symbols = file
+            .symbol_map()
+            .symbols()
+            .iter()
+            .map(|symbol| (symbol.address(), symbol.name().into()))
+            .collect();
+
+        let debug_data = match debug_file(&module.build_id) {
+            Some(path) => Some(fs::read(path)?),
+            None => None,
+        };
+        let debug = match &debug_data {
+            Some(debug) => object::File::parse(&**debug).map_err(module::invalid_data)?,
+            None => file,
+        };
+        let endian = if debug.is_little_endian() {
+            RunTimeEndian::Little
+        } else {
+            RunTimeEndian::Big
+        };
+        // Sections are copied out of the file, decompressing them if needed, so that the file
+        // can be dropped.
+        let dwarf = gimli::Dwarf::load(|id| -> Result<_, gimli::Error> {
+            let data = debug
+                .section_by_name(id.name())
+                .and_then(|section| section.uncompressed_data().ok());
+            let data: Arc<[u8]> = Arc::from(data.as_deref().unwrap_or(&[]));
+            Ok(EndianArcSlice::new(data, endian))
+        });
+        Ok(Some(DebugInfo {
+            context: dwarf.ok().and_then(|dwarf| Context::from_dwarf(dwarf).ok()),
+            symbols,
+            segments,
+        }))
+    }
+
+    /// The address in the binary of the byte at `offset` in the file.
+    fn address(&self, offset: u64) -> Option<u64> {
+        self.segments
+            .iter()
+            .find(|&&(start, size, _)| start <= offset && offset < start + size)
+            .map(|&(start, _, address)| address + (offset - start))
+    }
+
+    /// The name of the symbol containing `probe`, from the symbol table.
+    fn symbol(&self, probe: u64) -> Option<&str> {
+        let index = self
+            .symbols
+            .partition_point(|&(address, _)| address <= probe);
+        let (_, name) = &self.symbols[index.checked_sub(1)?];
+        Some(name)
+    }
+
+    fn resolve(&self, address: usize, probe: u64) -> Vec<Symbol> {
+        let mut symbols = Vec::new();
+        if let Some(context) = &self.context {
+            if let Ok(mut frames) = context.find_frames(probe).skip_all_loads() {
+                while let Ok(Some(frame)) = frames.next() {
+                    let name = frame
+                        .function
+                        .and_then(|function| function.raw_name().ok().map(demangle));
+                    let location = frame.location;
+                    symbols.push(Symbol {
+                        address,
+                        name,
+                        file: location.as_ref().and_then(|l| l.file).map(PathBuf::from),
+                        line: location.as_ref().and_then(|l| l.line),
+                    });
+                }
+            }
+        }
+        // Fall back to the symbol table for code without debug info.
+        let named = matches!(symbols.first(), Some(Symbol { name: Some(_), .. }));
+        if let (false, Some(symbol)) = (named, self.symbol(probe)) {
+            let name = Some(demangle(Cow::Borrowed(symbol)));
+            match symbols.first_mut() {
+                Some(first) => first.name = name,
+                None => symbols.push(Symbol {
+                    address,
+                    name,
+                    file: None,
+                    line: None,
+                }),
+            }
+        }
+        symbols
+    }
+}
+
+/// The separate debug info file for a binary with this build id, if one is installed.
+fn debug_file(build_id: &[u8]) -> Option<PathBuf> {
+    let (first, rest) = build_id.split_first()?;
+    let mut path = format!("/usr/lib/debug/.build-id/{:02x}/", first);
+    for byte in rest {
+        path.push_str(&format!("{:02x}", byte));
+    }
+    path.push_str(".debug");
+    let path = PathBuf::from(path);
+    if path.is_file() {
+        Some(path)
+    } else {
+        None
+    }
+}
+
+fn demangle(name: Cow<'_, str>) -> String {
+    format!("{:#}", rustc_demangle::demangle(&name))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[inline(never)]
+    fn target() -> usize {
+        target as fn() -> usize as usize
+    }
+
+    #[test]
+    fn symbolize_function() {
+        let mut symbolizer = Symbolizer::current().unwrap();
+        // Addresses are looked up as return addresses, one byte after the call.
+        let address = target() + 1;
+        let symbols = symbolizer.symbolize(address);
+        let symbol = symbols.last().unwrap();
+        assert_eq!(symbol.address, address);
+        let name = symbol.name.as_deref().unwrap();
+        assert!(name.ends_with("symbolize::tests::target"), "{}", name);
+        let file = symbol.file.as_deref().unwrap();
+        assert!(file.ends_with("src/symbolize.rs"), "{}", file.display());
+    }
+
+    #[test]
+    fn unknown_address() {
+        let mut symbolizer = Symbolizer::new(Vec::new());
+        assert_eq!(symbolizer.symbolize(0x1000), []);
+        let symbols = symbolizer.symbolize_stack(&[0x1000]);
+        assert_eq!(symbols.len(), 1);
+        assert_eq!(symbols[0].name, None);
+    }
+}
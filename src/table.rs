@@ -0,0 +1,58 @@
+use std::alloc::{handle_alloc_error, GlobalAlloc, Layout};
+use std::ptr;
+use std::slice;
+use std::sync::atomic::{AtomicPtr, Ordering};
+
+/// A fixed-size array allocated from the inner allocator the first time it is needed.
+///
+/// Tables are never freed, so references to their contents live as long as the table.
+pub(crate) struct Table<T> {
+    ptr: AtomicPtr<T>,
+    len: usize,
+}
+
+impl<T> Table<T> {
+    pub const fn new(len: usize) -> Self {
+        Table {
+            ptr: AtomicPtr::new(ptr::null_mut()),
+            len,
+        }
+    }
+
+    pub fn get(&self) -> Option<&[T]> {
+        let ptr = self.ptr.load(Ordering::Acquire);
+        if ptr.is_null() {
+            None
+        } else {
+            Some(unsafe { slice::from_raw_parts(ptr, self.len) })
+        }
+    }
+
+    /// Get the table, allocating it from `allocator` if necessary.
+    ///
+    /// # Safety
+    ///
+    /// A value of `T` with every byte zero must be valid.
+    pub unsafe fn get_or_init<A>(&self, allocator: &A) -> &[T]
+    where
+        A: GlobalAlloc,
+    {
+        if let Some(table) = self.get() {
+            return table;
+        }
+
+        let layout = Layout::array::<T>(self.len).unwrap();
+        let new = allocator.alloc_zeroed(layout) as *mut T;
+        if new.is_null() {
+            handle_alloc_error(layout);
+        }
+        if let Err(existing) =
+            self.ptr
+                .compare_exchange(ptr::null_mut(), new, Ordering::AcqRel, Ordering::Acquire)
+        {
+            allocator.dealloc(new as *mut u8, layout);
+            return slice::from_raw_parts(existing, self.len);
+        }
+        slice::from_raw_parts(new, self.len)
+    }
+}
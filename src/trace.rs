@@ -0,0 +1,619 @@
+//! A compact binary format for allocation traces.
+//!
+//! A trace starts with a 16 byte header:
+//!
+//! | offset | size | field                          |
+//! |--------|------|--------------------------------|
+//! | 0      | 8    | magic, `b"LATRACE\0"`          |
+//! | 8      | 2    | format version                 |
+//! | 10     | 2    | event record size              |
+//! | 12     | 4    | reserved                       |
+//!
+//! It is followed by a sequence of records, each starting with a one byte tag. Event records have
+//! a fixed size of 80 bytes:
+//!
+//! | offset | size | field                                       |
+//! |--------|------|---------------------------------------------|
+//! | 0      | 1    | tag: 1 alloc, 2 alloc_zeroed, 3 dealloc, 4 realloc |
+//! | 4      | 4    | stack id, or `u32::MAX` if none was captured |
+//! | 8      | 8    | sequence number                             |
+//! | 16     | 8    | timestamp                                   |
+//! | 24     | 8    | thread id                                   |
+//! | 32     | 8    | address (the old address for realloc)       |
+//! | 40     | 8    | size (the old size for realloc)             |
+//! | 48     | 8    | align                                       |
+//! | 56     | 8    | new address for realloc, otherwise zero     |
+//! | 64     | 8    | new size for realloc, otherwise zero        |
+//! | 72     | 8    | sampling weight, as an IEEE 754 double      |
+//!
+//! Each stack is written once, in a stack record preceding the first event that refers to it:
+//!
+//! | offset | size      | field                     |
+//! |--------|-----------|---------------------------|
+//! | 0      | 1         | tag: 5                    |
+//! | 4      | 4         | stack id                  |
+//! | 8      | 4         | number of frames `n`      |
+//! | 16     | 8 * `n`   | return addresses          |
+//!
+//! The executable mappings of the traced process, as found by [`Module::current`] when the trace
+//! was started, are written in module records following the header, so that the addresses can be
+//! symbolized later with a [`Symbolizer`](crate::Symbolizer):
+//!
+//! | offset   | size | field                         |
+//! |----------|------|-------------------------------|
+//! | 0        | 1    | tag: 6                        |
+//! | 2        | 2    | length of the build id `b`    |
+//! | 4        | 4    | length of the path `p`        |
+//! | 8        | 8    | start address                 |
+//! | 16       | 8    | end address                   |
+//! | 24       | 8    | file offset                   |
+//! | 32       | `b`  | build id                      |
+//! | 32 + `b` | `p`  | path, as UTF-8                |
+//!
+//! All integers are little-endian and unused bytes are zero.
+
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::io::{self, Read, Write};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Mutex, MutexGuard};
+
+use crate::exit::{self, ExitHook};
+use crate::local;
+use crate::{AllocEvent, Block, EventInfo, Module, Sink, StackId, MAX_FRAMES, STACK_CAPACITY};
+
+const MAGIC: [u8; 8] = *b"LATRACE\0";
+const VERSION: u16 = 1;
+const HEADER_SIZE: usize = 16;
+const RECORD_SIZE: usize = 80;
+const STACK_HEADER_SIZE: usize = 16;
+const MODULE_HEADER_SIZE: usize = 32;
+
+const TAG_ALLOC: u8 = 1;
+const TAG_ALLOC_ZEROED: u8 = 2;
+const TAG_DEALLOC: u8 = 3;
+const TAG_REALLOC: u8 = 4;
+const TAG_STACK: u8 = 5;
+const TAG_MODULE: u8 = 6;
+
+const NO_STACK: u32 = u32::MAX;
+
+const BUFFER_SIZE: usize = 64 * 1024;
+
+/// A sink that writes events in the binary trace format.
+///
+/// Records are staged in a fixed-size buffer embedded in the sink and written out whenever it
+/// fills up, so logging an event never allocates.
+pub struct TraceWriter<W> {
+    inner: Mutex<Inner<W>>,
+    flush_at_exit: AtomicBool,
+}
+
+struct Inner<W> {
+    writer: Option<W>,
+    buffer: [u8; BUFFER_SIZE],
+    len: usize,
+    written_stacks: [u64; STACK_CAPACITY / 64],
+}
+
+impl<W> TraceWriter<W> {
+    pub const fn new() -> Self {
+        TraceWriter {
+            inner: Mutex::new(Inner {
+                writer: None,
+                buffer: [0; BUFFER_SIZE],
+                len: 0,
+                written_stacks: [0; STACK_CAPACITY / 64],
+            }),
+            flush_at_exit: AtomicBool::new(false),
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Inner<W>> {
+        self.inner.lock().unwrap_or_else(|err| err.into_inner())
+    }
+}
+
+impl<W> TraceWriter<W>
+where
+    W: Write,
+{
+    /// Start a new trace in `writer`, flushing and returning the previously attached writer.
+    ///
+    /// The module map of the process is written at the start of the trace, so modules loaded
+    /// later are missing from it.
+    pub fn attach(&self, writer: W) -> io::Result<Option<W>> {
+        // Reading the module map allocates, so it must be done before taking the lock.
+        let modules = Module::current().unwrap_or_default();
+        unlogged(|| {
+            let mut inner = self.lock();
+            inner.flush()?;
+            let previous = inner.writer.replace(writer);
+            inner.written_stacks = [0; STACK_CAPACITY / 64];
+            inner.buffer[..HEADER_SIZE].copy_from_slice(&header());
+            inner.len = HEADER_SIZE;
+            for module in &modules {
+                inner.write_module(module)?;
+            }
+            Ok(previous)
+        })
+    }
+
+    /// Flush buffered records and stop tracing, returning the attached writer.
+    pub fn detach(&self) -> io::Result<Option<W>> {
+        unlogged(|| {
+            let mut inner = self.lock();
+            inner.flush()?;
+            Ok(inner.writer.take())
+        })
+    }
+
+    /// Write all buffered records to the attached writer.
+    pub fn flush(&self) -> io::Result<()> {
+        unlogged(|| self.lock().flush())
+    }
+}
+
+impl<W> TraceWriter<W>
+where
+    W: Write + Send,
+{
+    /// Write out the buffered records when the process exits, so the end of the trace is not lost
+    /// if the writer is never detached.
+    pub fn flush_at_exit(&'static self) {
+        if !self.flush_at_exit.swap(true, Ordering::SeqCst) {
+            exit::register(self);
+        }
+    }
+}
+
+impl<W> ExitHook for TraceWriter<W>
+where
+    W: Write + Send,
+{
+    fn at_exit(&self) {
+        let _ = TraceWriter::flush(self);
+    }
+}
+
+/// Run `f` as part of the allocator's own bookkeeping. The writer may allocate, and if this sink
+/// belongs to the global allocator, logging those allocations would lock it again.
+fn unlogged<F, R>(f: F) -> R
+where
+    F: FnOnce() -> R,
+{
+    let mut f = Some(f);
+    local::bookkeeping(|| (f.take().unwrap())()).unwrap_or_else(|| (f.take().unwrap())())
+}
+
+impl<W> Inner<W>
+where
+    W: Write,
+{
+    fn flush(&mut self) -> io::Result<()> {
+        if let Some(writer) = &mut self.writer {
+            writer.write_all(&self.buffer[..self.len])?;
+            writer.flush()?;
+        }
+        self.len = 0;
+        Ok(())
+    }
+
+    fn reserve(&mut self, size: usize) -> io::Result<&mut [u8]> {
+        if self.len + size > BUFFER_SIZE {
+            self.flush()?;
+        }
+        let record = &mut self.buffer[self.len..][..size];
+        record.fill(0);
+        self.len += size;
+        Ok(record)
+    }
+
+    fn write_stack(&mut self, id: StackId) -> io::Result<()> {
+        let index = id.as_raw() as usize;
+        let (word, bit) = (index / 64, 1 << (index % 64));
+        if self.written_stacks[word] & bit != 0 {
+            return Ok(());
+        }
+        let frames = id.frames().unwrap_or(&[]);
+        let record = self.reserve(STACK_HEADER_SIZE + 8 * frames.len())?;
+        record[0] = TAG_STACK;
+        record[4..8].copy_from_slice(&id.as_raw().to_le_bytes());
+        record[8..12].copy_from_slice(&(frames.len() as u32).to_le_bytes());
+        for (chunk, &frame) in record[STACK_HEADER_SIZE..].chunks_mut(8).zip(frames) {
+            chunk.copy_from_slice(&(frame as u64).to_le_bytes());
+        }
+        self.written_stacks[word] |= bit;
+        Ok(())
+    }
+
+    fn write_module(&mut self, module: &Module) -> io::Result<()> {
+        let path = module.path.to_string_lossy();
+        let path = path.as_bytes();
+        let build_id = &module.build_id;
+        let size = MODULE_HEADER_SIZE + build_id.len() + path.len();
+        if build_id.len() > u16::MAX as usize || size > BUFFER_SIZE {
+            return Ok(());
+        }
+        let record = self.reserve(size)?;
+        record[0] = TAG_MODULE;
+        record[2..4].copy_from_slice(&(build_id.len() as u16).to_le_bytes());
+        record[4..8].copy_from_slice(&(path.len() as u32).to_le_bytes());
+        record[8..16].copy_from_slice(&(module.start as u64).to_le_bytes());
+        record[16..24].copy_from_slice(&(module.end as u64).to_le_bytes());
+        record[24..32].copy_from_slice(&module.offset.to_le_bytes());
+        let (id, rest) = record[MODULE_HEADER_SIZE..].split_at_mut(build_id.len());
+        id.copy_from_slice(build_id);
+        rest.copy_from_slice(path);
+        Ok(())
+    }
+
+    fn write_event(&mut self, event: &AllocEvent) -> io::Result<()> {
+        if let Some(id) = event.info().backtrace {
+            self.write_stack(id)?;
+        }
+        encode(event, self.reserve(RECORD_SIZE)?);
+        Ok(())
+    }
+}
+
+impl<W> Sink for TraceWriter<W>
+where
+    W: Write,
+{
+    fn log(&self, event: &AllocEvent) {
+        let mut inner = self.lock();
+        if inner.writer.is_some() {
+            let _ = inner.write_event(event);
+        }
+    }
+
+    fn flush(&self) {
+        let _ = TraceWriter::flush(self);
+    }
+}
+
+impl<W> Default for TraceWriter<W> {
+    fn default() -> Self {
+        TraceWriter::new()
+    }
+}
+
+/// Identifies a stack within a trace. It is unrelated to the [`StackId`]s of the reading process,
+/// and the frames are looked up with [`TraceReader::stack`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct TraceStackId(u32);
+
+/// An event read from a trace.
+#[derive(Clone, Copy, Debug)]
+pub struct TraceEvent {
+    /// The event, with no backtrace, since the stack ids in a trace cannot be resolved in the
+    /// reading process.
+    pub event: AllocEvent,
+    /// The stack recorded for the event, if one was captured.
+    pub stack: Option<TraceStackId>,
+}
+
+/// Parses a binary trace back into events.
+pub struct TraceReader<R> {
+    reader: R,
+    version: u16,
+    stacks: HashMap<TraceStackId, Vec<usize>>,
+    modules: Vec<Module>,
+}
+
+impl TraceStackId {
+    pub const fn as_raw(self) -> u32 {
+        self.0
+    }
+}
+
+impl<R> TraceReader<R>
+where
+    R: Read,
+{
+    /// Read and validate the header of a trace.
+    pub fn new(mut reader: R) -> io::Result<Self> {
+        let mut header = [0; HEADER_SIZE];
+        reader.read_exact(&mut header)?;
+        if header[..8] != MAGIC {
+            return Err(invalid_data("not an allocation trace"));
+        }
+        let version = u16::from_le_bytes([header[8], header[9]]);
+        if version != VERSION {
+            return Err(invalid_data("unsupported trace version"));
+        }
+        if u16::from_le_bytes([header[10], header[11]]) as usize != RECORD_SIZE {
+            return Err(invalid_data("unexpected record size"));
+        }
+        Ok(TraceReader {
+            reader,
+            version,
+            stacks: HashMap::new(),
+            modules: Vec::new(),
+        })
+    }
+
+    pub fn version(&self) -> u16 {
+        self.version
+    }
+
+    /// The return addresses of a stack referenced by an event read from this trace, innermost
+    /// first.
+    pub fn stack(&self, id: TraceStackId) -> Option<&[usize]> {
+        self.stacks.get(&id).map(Vec::as_slice)
+    }
+
+    /// The modules of the traced process read so far. Every module is read by the time the first
+    /// event is returned.
+    pub fn modules(&self) -> &[Module] {
+        &self.modules
+    }
+
+    fn read_module(&mut self, record: &mut [u8; RECORD_SIZE]) -> io::Result<()> {
+        self.reader.read_exact(&mut record[1..MODULE_HEADER_SIZE])?;
+        let build_id_len = u16::from_le_bytes([record[2], record[3]]) as usize;
+        let path_len = read_u32(&record[4..]) as usize;
+        if MODULE_HEADER_SIZE + build_id_len + path_len > BUFFER_SIZE {
+            return Err(invalid_data("module record is too large"));
+        }
+        let mut build_id = vec![0; build_id_len];
+        self.reader.read_exact(&mut build_id)?;
+        let mut path = vec![0; path_len];
+        self.reader.read_exact(&mut path)?;
+        self.modules.push(Module {
+            path: PathBuf::from(String::from_utf8_lossy(&path).into_owned()),
+            start: read_u64(&record[8..]) as usize,
+            end: read_u64(&record[16..]) as usize,
+            offset: read_u64(&record[24..]),
+            build_id,
+        });
+        Ok(())
+    }
+
+    fn read_record(&mut self) -> io::Result<Option<TraceEvent>> {
+        loop {
+            let mut record = [0; RECORD_SIZE];
+            match self.reader.read_exact(&mut record[..1]) {
+                Ok(()) => (),
+                Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+                Err(err) => return Err(err),
+            }
+            if record[0] == TAG_MODULE {
+                self.read_module(&mut record)?;
+                continue;
+            }
+            if record[0] != TAG_STACK {
+                self.reader.read_exact(&mut record[1..])?;
+                return decode(&record).map(Some);
+            }
+
+            self.reader.read_exact(&mut record[1..STACK_HEADER_SIZE])?;
+            let id = TraceStackId(read_u32(&record[4..]));
+            let len = read_u32(&record[8..]) as usize;
+            if len > MAX_FRAMES {
+                return Err(invalid_data("stack has too many frames"));
+            }
+            let mut frames = vec![0; 8 * len];
+            self.reader.read_exact(&mut frames)?;
+            let frames = frames.chunks(8).map(|chunk| read_u64(chunk) as usize);
+            self.stacks.insert(id, frames.collect());
+        }
+    }
+}
+
+impl<R> Iterator for TraceReader<R>
+where
+    R: Read,
+{
+    type Item = io::Result<TraceEvent>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.read_record().transpose()
+    }
+}
+
+fn header() -> [u8; HEADER_SIZE] {
+    let mut header = [0; HEADER_SIZE];
+    header[..8].copy_from_slice(&MAGIC);
+    header[8..10].copy_from_slice(&VERSION.to_le_bytes());
+    header[10..12].copy_from_slice(&(RECORD_SIZE as u16).to_le_bytes());
+    header
+}
+
+fn encode(event: &AllocEvent, record: &mut [u8]) {
+    let (tag, old, new) = match *event {
+        AllocEvent::Alloc { block, .. } => (TAG_ALLOC, block, None),
+        AllocEvent::AllocZeroed { block, .. } => (TAG_ALLOC_ZEROED, block, None),
+        AllocEvent::Dealloc { block, .. } => (TAG_DEALLOC, block, None),
+        AllocEvent::Realloc { old, new, .. } => (TAG_REALLOC, old, Some(new)),
+    };
+    let info = event.info();
+    let stack = info.backtrace.map_or(NO_STACK, StackId::as_raw);
+    let new = new.unwrap_or(Block {
+        address: 0,
+        size: 0,
+        align: 0,
+    });
+
+    record[0] = tag;
+    record[4..8].copy_from_slice(&stack.to_le_bytes());
+    let fields = [
+        info.sequence,
+        info.timestamp,
+        info.thread_id,
+        old.address as u64,
+        old.size as u64,
+        old.align as u64,
+        new.address as u64,
+        new.size as u64,
+        info.weight.to_bits(),
+    ];
+    for (chunk, field) in record[8..].chunks_mut(8).zip(fields) {
+        chunk.copy_from_slice(&field.to_le_bytes());
+    }
+}
+
+fn decode(record: &[u8; RECORD_SIZE]) -> io::Result<TraceEvent> {
+    let field = |index: usize| read_u64(&record[8 + 8 * index..]);
+    let stack = match read_u32(&record[4..]) {
+        NO_STACK => None,
+        stack => Some(TraceStackId(stack)),
+    };
+    let info = EventInfo {
+        sequence: field(0),
+        timestamp: field(1),
+        thread_id: field(2),
+        backtrace: None,
+        weight: f64::from_bits(field(8)),
+    };
+    let block = Block {
+        address: field(3) as usize,
+        size: field(4) as usize,
+        align: field(5) as usize,
+    };
+    let event = match record[0] {
+        TAG_ALLOC => AllocEvent::Alloc { block, info },
+        TAG_ALLOC_ZEROED => AllocEvent::AllocZeroed { block, info },
+        TAG_DEALLOC => AllocEvent::Dealloc { block, info },
+        TAG_REALLOC => AllocEvent::Realloc {
+            old: block,
+            new: Block {
+                address: field(6) as usize,
+                size: field(7) as usize,
+                align: block.align,
+            },
+            info,
+        },
+        _ => return Err(invalid_data("unknown record tag")),
+    };
+    Ok(TraceEvent { event, stack })
+}
+
+fn read_u32(bytes: &[u8]) -> u32 {
+    u32::from_le_bytes(bytes[..4].try_into().unwrap())
+}
+
+fn read_u64(bytes: &[u8]) -> u64 {
+    u64::from_le_bytes(bytes[..8].try_into().unwrap())
+}
+
+fn invalid_data(message: &'static str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stack;
+
+    fn info(backtrace: Option<StackId>) -> EventInfo {
+        EventInfo {
+            thread_id: 3,
+            timestamp: 1_000_000,
+            sequence: 7,
+            backtrace,
+            weight: 2.5,
+        }
+    }
+
+    fn block(address: usize, size: usize) -> Block {
+        Block {
+            address,
+            size,
+            align: 8,
+        }
+    }
+
+    #[test]
+    fn encode_decode() {
+        let events = [
+            AllocEvent::Alloc {
+                block: block(0x1000, 16),
+                info: info(None),
+            },
+            AllocEvent::AllocZeroed {
+                block: block(0x2000, 32),
+                info: info(None),
+            },
+            AllocEvent::Dealloc {
+                block: block(0x3000, 48),
+                info: info(None),
+            },
+            AllocEvent::Realloc {
+                old: block(0x4000, 64),
+                new: block(0x5000, 128),
+                info: info(None),
+            },
+        ];
+        for event in &events {
+            let mut record = [0; RECORD_SIZE];
+            encode(event, &mut record);
+            let decoded = decode(&record).unwrap();
+            assert_eq!(decoded.stack, None);
+            assert_eq!(decoded.event.name(), event.name());
+            assert_eq!(decoded.event.block(), event.block());
+            let (info, expected) = (decoded.event.info(), event.info());
+            assert_eq!(info.thread_id, expected.thread_id);
+            assert_eq!(info.timestamp, expected.timestamp);
+            assert_eq!(info.sequence, expected.sequence);
+            assert_eq!(info.weight, expected.weight);
+            if let AllocEvent::Realloc { old, .. } = decoded.event {
+                assert_eq!(old, block(0x4000, 64));
+            }
+        }
+    }
+
+    #[test]
+    fn decode_unknown_tag() {
+        let mut record = [0; RECORD_SIZE];
+        record[0] = 0xff;
+        assert!(decode(&record).is_err());
+    }
+
+    #[test]
+    fn round_trip() {
+        let frames = [0x1111, 0x2222, 0x3333];
+        let id = stack::intern(&frames).unwrap();
+        let module = Module {
+            path: PathBuf::from("/usr/lib/libexample.so"),
+            start: 0x7f00_0000_0000,
+            end: 0x7f00_0001_0000,
+            offset: 0x1000,
+            build_id: vec![0xde, 0xad, 0xbe, 0xef],
+        };
+
+        let writer = TraceWriter::new();
+        writer.attach(Vec::new()).unwrap();
+        writer.lock().write_module(&module).unwrap();
+        let event = AllocEvent::Alloc {
+            block: block(0x1000, 16),
+            info: info(Some(id)),
+        };
+        writer.log(&event);
+        writer.log(&event);
+        let trace = writer.detach().unwrap().unwrap();
+
+        let mut reader = TraceReader::new(trace.as_slice()).unwrap();
+        assert_eq!(reader.version(), VERSION);
+        let events = reader.by_ref().collect::<io::Result<Vec<_>>>().unwrap();
+        assert_eq!(events.len(), 2);
+        assert!(reader.modules().contains(&module));
+        let stack = events[0].stack.unwrap();
+        assert_eq!(stack.as_raw(), id.as_raw());
+        assert_eq!(events[1].stack, Some(stack));
+        assert_eq!(events[0].event.info().backtrace, None);
+        assert_eq!(reader.stack(stack), Some(&frames[..]));
+    }
+
+    #[test]
+    fn bad_header() {
+        assert!(TraceReader::new(&[0u8; HEADER_SIZE][..]).is_err());
+        for version in [VERSION - 1, VERSION + 1] {
+            let mut trace = header();
+            trace[8..10].copy_from_slice(&version.to_le_bytes());
+            assert!(TraceReader::new(&trace[..]).is_err());
+        }
+    }
+}
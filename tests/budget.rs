@@ -0,0 +1,54 @@
+use std::alloc::System;
+use std::process::Command;
+
+use logging_allocator::{run_suppressed, BudgetAction, BudgetExceeded, LoggingAllocator};
+
+#[global_allocator]
+static ALLOC: LoggingAllocator<System> = LoggingAllocator::with_allocator(System, false);
+
+/// Set when the test binary is run again to check the report in a separate process.
+const CHILD: &str = "LOGGING_ALLOCATOR_BUDGET_CHILD";
+
+const SIZE: usize = 1 << 20;
+
+fn ignore(_: &BudgetExceeded) {}
+
+#[test]
+fn report_live_callsites() {
+    if std::env::var_os(CHILD).is_some() {
+        ALLOC.set_memory_limit(
+            ALLOC.stats().bytes_in_use + 2 * SIZE,
+            BudgetAction::Hook(ignore),
+        );
+        let kept = vec![1u8; SIZE];
+        drop(vec![2u8; 2 * SIZE]);
+        ALLOC.clear_memory_limit();
+        assert_eq!(ALLOC.memory_limit_exceeded(), 1);
+
+        // Suppressing logging does not lift the limit.
+        ALLOC.set_memory_limit(
+            ALLOC.stats().bytes_in_use + SIZE,
+            BudgetAction::Hook(ignore),
+        );
+        run_suppressed(|| drop(vec![3u8; 2 * SIZE]));
+        ALLOC.clear_memory_limit();
+        assert_eq!(ALLOC.memory_limit_exceeded(), 2);
+        drop(kept);
+        return;
+    }
+    let output = Command::new(std::env::current_exe().unwrap())
+        .args(["--exact", "report_live_callsites", "--nocapture"])
+        .env(CHILD, "1")
+        .output()
+        .unwrap();
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(output.status.success(), "{}", stderr);
+    assert_eq!(stderr.matches("would exceed the memory limit").count(), 2);
+    assert_eq!(
+        stderr.matches("top live callsites:").count(),
+        2,
+        "{}",
+        stderr
+    );
+    assert!(stderr.contains(&format!("{} bytes live from 1 allocations", SIZE)));
+}
@@ -0,0 +1,36 @@
+use std::alloc::System;
+
+use logging_allocator::{Callsite, CallsiteOrder, LoggingAllocator, StackId};
+
+#[global_allocator]
+static ALLOC: LoggingAllocator<System> = LoggingAllocator::with_allocator(System, false);
+
+const SIZE: usize = 1 << 20;
+
+fn live_bytes(stack: StackId) -> usize {
+    let callsites = ALLOC.top_callsites(usize::MAX, CallsiteOrder::LiveBytes);
+    let callsite = callsites.iter().find(|callsite| callsite.stack == stack);
+    callsite.map_or(0, |callsite| callsite.live_bytes)
+}
+
+fn top() -> Callsite {
+    ALLOC.top_callsites(1, CallsiteOrder::LiveBytes)[0]
+}
+
+#[test]
+fn live_bytes_follow_blocks() {
+    ALLOC.enable_callsite_tracking();
+    let mut v = Vec::<u8>::with_capacity(SIZE);
+    let allocated = top();
+    assert!(allocated.live_bytes >= SIZE);
+
+    // The reallocated block is attributed to the stack that grew it.
+    v.reserve_exact(2 * SIZE);
+    let grown = top();
+    assert_ne!(grown.stack, allocated.stack);
+    assert!(grown.live_bytes >= 2 * SIZE);
+    assert_eq!(live_bytes(allocated.stack), allocated.live_bytes - SIZE);
+
+    drop(v);
+    assert_eq!(live_bytes(grown.stack), grown.live_bytes - 2 * SIZE);
+}
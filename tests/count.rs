@@ -0,0 +1,41 @@
+use std::alloc::System;
+
+use logging_allocator::{
+    assert_allocations, count_allocations, AllocEvent, LoggingAllocator, Sink,
+};
+
+/// A sink that allocates while logging, like one that formats events.
+struct FormatSink;
+
+impl Sink for FormatSink {
+    fn log(&self, event: &AllocEvent) {
+        drop(format!("{} {:?}", event.name(), event.block()));
+    }
+}
+
+#[global_allocator]
+static ALLOC: LoggingAllocator<System, FormatSink> =
+    LoggingAllocator::with_sink(System, FormatSink, true);
+
+#[test]
+fn sink_allocations_are_not_counted() {
+    let v = vec![1u8];
+    let counts = count_allocations(|| drop(v));
+    assert_eq!(counts.allocations, 0);
+    assert_eq!(counts.deallocations, 1);
+    assert_eq!(counts.reallocations, 0);
+
+    let mut v = Vec::with_capacity(1);
+    v.push(1u8);
+    let counts = count_allocations(|| v.push(2));
+    assert_eq!(counts.allocations, 0);
+    assert_eq!(counts.reallocations, 1);
+
+    let numbers = [1, 2, 3];
+    assert_allocations!(max = 0, {
+        numbers.iter().sum::<i32>();
+    });
+    assert_allocations!(max = 1, {
+        drop(Box::new(5u64));
+    });
+}
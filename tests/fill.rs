@@ -0,0 +1,33 @@
+use std::alloc::{GlobalAlloc, Layout};
+
+use logging_allocator::LoggingAllocator;
+
+#[global_allocator]
+static ALLOC: LoggingAllocator = LoggingAllocator::new(false);
+
+const PATTERN: u8 = 0xa5;
+
+#[test]
+fn alloc_fill() {
+    ALLOC.set_alloc_fill(PATTERN);
+    assert_eq!(ALLOC.alloc_fill(), Some(PATTERN));
+    unsafe {
+        let layout = Layout::from_size_align(64, 8).unwrap();
+        let ptr = ALLOC.alloc(layout);
+        assert!((0..64).all(|offset| *ptr.add(offset) == PATTERN));
+
+        // Only the grown tail of a reallocated block is filled.
+        ptr.write_bytes(1, 64);
+        let ptr = ALLOC.realloc(ptr, layout, 4096);
+        assert!((0..64).all(|offset| *ptr.add(offset) == 1));
+        assert!((64..4096).all(|offset| *ptr.add(offset) == PATTERN));
+        ALLOC.dealloc(ptr, Layout::from_size_align(4096, 8).unwrap());
+
+        let ptr = ALLOC.alloc_zeroed(layout);
+        assert!((0..64).all(|offset| *ptr.add(offset) == 0));
+        ALLOC.dealloc(ptr, layout);
+    }
+    assert_eq!(vec![0u8; 1000], [0; 1000]);
+    ALLOC.clear_alloc_fill();
+    assert_eq!(ALLOC.alloc_fill(), None);
+}
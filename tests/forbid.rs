@@ -0,0 +1,75 @@
+use std::alloc::System;
+use std::process::Command;
+
+use logging_allocator::{run_suppressed, AllocEvent, ForbiddenAction, LoggingAllocator, Sink};
+
+/// A sink that symbolizes the backtrace of each event, which allocates.
+struct FormatSink;
+
+impl Sink for FormatSink {
+    fn log(&self, event: &AllocEvent) {
+        drop(event.to_string());
+    }
+}
+
+#[global_allocator]
+static ALLOC: LoggingAllocator<System, FormatSink> =
+    LoggingAllocator::with_sink(System, FormatSink, false);
+
+/// Set when the test binary is run again to check the default action in a separate process.
+const CHILD: &str = "LOGGING_ALLOCATOR_FORBID_CHILD";
+
+fn forbidden_box() {
+    let _guard = ALLOC.forbid_allocations();
+    drop(Box::new(5u64));
+}
+
+#[test]
+fn logging_in_forbid_zone() {
+    if std::env::var_os(CHILD).is_some() {
+        return;
+    }
+    ALLOC.set_forbidden_action(ForbiddenAction::Record);
+    ALLOC.enable_logging_on_current_thread();
+    let before = ALLOC.forbidden_allocations();
+    forbidden_box();
+    assert_eq!(ALLOC.forbidden_allocations() - before, 1);
+
+    // Suppressing logging does not permit allocations, but the sink's own allocations are.
+    let before = ALLOC.forbidden_allocations();
+    {
+        let _guard = ALLOC.forbid_allocations();
+        run_suppressed(|| drop(Box::new(5u64)));
+    }
+    assert_eq!(ALLOC.forbidden_allocations() - before, 1);
+    ALLOC.reset_logging_on_current_thread();
+}
+
+/// With the default action the report is printed while the sink may be symbolizing.
+#[test]
+fn log_action_with_logging() {
+    if std::env::var_os(CHILD).is_some() {
+        ALLOC.enable_logging_on_current_thread();
+        forbidden_box();
+        assert_eq!(ALLOC.forbidden_allocations(), 1);
+        return;
+    }
+    let output = Command::new(std::env::current_exe().unwrap())
+        .args([
+            "--exact",
+            "log_action_with_logging",
+            "--test-threads=1",
+            "--nocapture",
+        ])
+        .env(CHILD, "1")
+        .output()
+        .unwrap();
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(output.status.success(), "{}", stderr);
+    assert_eq!(
+        stderr.matches("in a no-allocation zone").count(),
+        1,
+        "{}",
+        stderr
+    );
+}
@@ -0,0 +1,88 @@
+use std::alloc::{GlobalAlloc, Layout};
+use std::process::Command;
+
+use logging_allocator::LoggingAllocator;
+
+#[global_allocator]
+static ALLOC: LoggingAllocator = LoggingAllocator::new(false);
+
+/// Set when the test binary is run again to check the reports in a separate process.
+const CHILD: &str = "LOGGING_ALLOCATOR_FREE_CHECK_CHILD";
+
+#[test]
+fn free_checking() {
+    if std::env::var_os(CHILD).is_some() {
+        return;
+    }
+    // Blocks allocated before checking is enabled can still be reallocated and freed.
+    let mut v = vec![1u8];
+    let boxed = Box::new([0u8; 64]);
+    ALLOC.enable_free_checking();
+    let before = ALLOC.bad_frees();
+    v.push(2);
+    assert_eq!(v, [1, 2]);
+    drop(boxed);
+    assert!(ALLOC.bad_frees() - before >= 2);
+    drop(v);
+
+    // A double free is dropped.
+    unsafe {
+        let layout = Layout::new::<[u64; 4]>();
+        let ptr = ALLOC.alloc(layout);
+        assert!(!ptr.is_null());
+        ALLOC.dealloc(ptr, layout);
+        let before = ALLOC.bad_frees();
+        ALLOC.dealloc(ptr, layout);
+        assert_eq!(ALLOC.bad_frees() - before, 1);
+        assert!(ALLOC.realloc(ptr, layout, 64).is_null());
+        assert_eq!(ALLOC.bad_frees() - before, 2);
+    }
+}
+
+#[test]
+fn layout_mismatch() {
+    if std::env::var_os(CHILD).is_none() {
+        let output = Command::new(std::env::current_exe().unwrap())
+            .args(["--exact", "layout_mismatch", "--nocapture"])
+            .env(CHILD, "1")
+            .output()
+            .unwrap();
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        assert!(output.status.success(), "{}", stderr);
+        assert_eq!(
+            stderr.matches("layout mismatch freeing").count(),
+            2,
+            "{}",
+            stderr
+        );
+        assert!(stderr.contains("with size 24 and align 8"), "{}", stderr);
+        assert!(stderr.contains("with size 64 and align 16"), "{}", stderr);
+        assert!(
+            stderr.contains("allocated with size 32 and align 8"),
+            "{}",
+            stderr
+        );
+        return;
+    }
+    ALLOC.enable_free_checking();
+    let before = ALLOC.layout_mismatches();
+    unsafe {
+        let layout = Layout::from_size_align(32, 8).unwrap();
+        let ptr = ALLOC.alloc(layout);
+        assert!(!ptr.is_null());
+        // A mismatched reallocation is still forwarded.
+        let wrong = Layout::from_size_align(24, 8).unwrap();
+        let ptr = ALLOC.realloc(ptr, wrong, 64);
+        assert!(!ptr.is_null());
+        assert_eq!(ALLOC.layout_mismatches() - before, 1);
+
+        let wrong = Layout::from_size_align(64, 16).unwrap();
+        ALLOC.dealloc(ptr, wrong);
+        assert_eq!(ALLOC.layout_mismatches() - before, 2);
+
+        let layout = Layout::from_size_align(48, 8).unwrap();
+        let ptr = ALLOC.alloc(layout);
+        ALLOC.dealloc(ptr, layout);
+    }
+    assert_eq!(ALLOC.layout_mismatches() - before, 2);
+}
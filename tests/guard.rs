@@ -0,0 +1,55 @@
+#![cfg(target_os = "linux")]
+
+use std::alloc::{GlobalAlloc, Layout};
+use std::fs;
+
+use logging_allocator::{GuardPageAllocator, LoggingAllocator};
+
+#[global_allocator]
+static ALLOC: LoggingAllocator<GuardPageAllocator> =
+    LoggingAllocator::with_allocator(GuardPageAllocator::new(), false);
+
+/// The resident set size of the process, in pages.
+fn resident_pages() -> usize {
+    let statm = fs::read_to_string("/proc/self/statm").unwrap();
+    statm.split_whitespace().nth(1).unwrap().parse().unwrap()
+}
+
+#[test]
+fn freed_blocks_release_memory() {
+    const SIZE: usize = 4000;
+    const BLOCKS: u64 = 20_000;
+
+    ALLOC.allocator().set_size_filter(SIZE..=SIZE);
+    ALLOC.allocator().set_sample_rate(1);
+    let guarded = ALLOC.allocator().guarded_allocations();
+    let before = resident_pages();
+    for _ in 0..BLOCKS {
+        let block = vec![1u8; SIZE];
+        assert_eq!(block[SIZE - 1], 1);
+    }
+    let after = resident_pages();
+    ALLOC.allocator().set_sample_rate(0);
+
+    assert_eq!(ALLOC.allocator().guarded_allocations() - guarded, BLOCKS);
+    // Each block touched one page, so keeping them would add 20,000 pages.
+    assert!(
+        after.saturating_sub(before) < 2_000,
+        "resident set grew from {} to {} pages",
+        before,
+        after
+    );
+}
+
+#[test]
+fn nothing_guarded_by_default() {
+    let allocator = GuardPageAllocator::new();
+    let layout = Layout::from_size_align(4000, 8).unwrap();
+    unsafe {
+        let ptr = allocator.alloc(layout);
+        assert!(!ptr.is_null());
+        allocator.dealloc(ptr, layout);
+    }
+    assert_eq!(allocator.sample_rate(), 0);
+    assert_eq!(allocator.guarded_allocations(), 0);
+}
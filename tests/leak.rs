@@ -0,0 +1,32 @@
+use std::alloc::System;
+use std::sync::atomic::{AtomicPtr, Ordering};
+
+use logging_allocator::LoggingAllocator;
+
+#[global_allocator]
+static ALLOC: LoggingAllocator<System> = LoggingAllocator::with_allocator(System, false);
+
+static KEPT: AtomicPtr<[u64; 8]> = AtomicPtr::new(std::ptr::null_mut());
+
+#[test]
+fn leaks_are_reported() {
+    ALLOC.enable_leak_tracking();
+    let leaked = Box::into_raw(Box::new([1u64; 4])) as usize;
+    KEPT.store(Box::into_raw(Box::new([2u64; 8])), Ordering::SeqCst);
+    let freed = Box::new([3u64; 2]);
+    let freed_address = &*freed as *const _ as usize;
+    drop(freed);
+    ALLOC.disable_leak_tracking();
+
+    let report = ALLOC.leaks();
+    let blocks: Vec<_> = report.leaks.iter().flat_map(|leak| &leak.blocks).collect();
+    let block = blocks.iter().find(|block| block.address == leaked).unwrap();
+    assert_eq!(block.size, 32);
+    let kept = KEPT.load(Ordering::SeqCst) as usize;
+    assert!(blocks.iter().all(|block| block.address != kept));
+    assert!(blocks.iter().all(|block| block.address != freed_address));
+    assert!(report.reachable >= 1);
+
+    let line = format!("[address={:#x}, size=32, align=8]", leaked);
+    assert!(report.to_string().contains(&line));
+}
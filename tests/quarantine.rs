@@ -0,0 +1,88 @@
+use std::process::Command;
+use std::ptr;
+
+use logging_allocator::{LoggingAllocator, MAX_QUARANTINED};
+
+#[global_allocator]
+static ALLOC: LoggingAllocator = LoggingAllocator::new(false);
+
+/// Set when the test binary is run again to write to freed blocks in a separate process.
+const CHILD: &str = "LOGGING_ALLOCATOR_QUARANTINE_CHILD";
+
+const SIZE: usize = 64 * 1024;
+
+fn free(len: usize) -> *mut u8 {
+    let mut v = vec![0u8; len];
+    let ptr = v.as_mut_ptr();
+    drop(v);
+    ptr
+}
+
+unsafe fn write(ptr: *mut u8) {
+    ptr::write_volatile(ptr.add(1), 0xff);
+}
+
+fn writes() -> u64 {
+    ALLOC.use_after_free_writes()
+}
+
+#[test]
+fn use_after_free() {
+    if std::env::var_os(CHILD).is_some() {
+        // A write to a freed block is found when the quarantine is flushed.
+        ALLOC.enable_quarantine(4 * SIZE);
+        let block = free(SIZE);
+        assert!(ALLOC.quarantined_bytes() >= SIZE);
+        unsafe { write(block) };
+        assert_eq!(ALLOC.flush_quarantine(), 1);
+        assert_eq!(writes(), 1);
+        assert_eq!(ALLOC.quarantined_bytes(), 0);
+
+        // The oldest blocks are released once the quarantine is over its size limit.
+        ALLOC.enable_quarantine(3 * SIZE + SIZE / 2);
+        let first = free(SIZE);
+        unsafe { write(first) };
+        let second = free(SIZE);
+        unsafe { write(second) };
+        free(SIZE);
+        assert_eq!(writes(), 1);
+        free(SIZE);
+        assert_eq!(writes(), 2);
+        assert_eq!(ALLOC.flush_quarantine(), 1);
+        assert_eq!(writes(), 3);
+
+        // Or once it holds the maximum number of blocks.
+        ALLOC.enable_quarantine(usize::MAX);
+        let blocks: Vec<Box<u64>> = (0..=MAX_QUARANTINED).map(|_| Box::new(0)).collect();
+        let first = &*blocks[0] as *const u64 as *mut u8;
+        let mut blocks = blocks.into_iter();
+        drop(blocks.next());
+        unsafe { write(first) };
+        blocks.for_each(drop);
+        assert_eq!(writes(), 4);
+        assert_eq!(ALLOC.flush_quarantine(), 0);
+
+        // A reallocated block is moved, so the old block is quarantined.
+        let mut v = Vec::<u8>::with_capacity(SIZE);
+        let old = v.as_mut_ptr();
+        v.reserve_exact(SIZE + 1);
+        assert_ne!(v.as_mut_ptr(), old);
+        unsafe { write(old) };
+        drop(v);
+
+        // Disabling the quarantine checks and releases every block in it.
+        ALLOC.disable_quarantine();
+        assert_eq!(writes(), 5);
+        assert_eq!(ALLOC.quarantined_bytes(), 0);
+        assert_eq!(ALLOC.quarantine_max_bytes(), 0);
+        return;
+    }
+    let output = Command::new(std::env::current_exe().unwrap())
+        .args(["--exact", "use_after_free", "--nocapture"])
+        .env(CHILD, "1")
+        .output()
+        .unwrap();
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(output.status.success(), "{}", stderr);
+    assert_eq!(stderr.matches("use after free").count(), 5, "{}", stderr);
+}
@@ -0,0 +1,42 @@
+use std::process::Command;
+
+use logging_allocator::LoggingAllocator;
+
+#[global_allocator]
+static ALLOC: LoggingAllocator = LoggingAllocator::new(false);
+
+/// Set when the test binary is run again to overflow a block in a separate process.
+const CHILD: &str = "LOGGING_ALLOCATOR_RED_ZONE_CHILD";
+
+#[inline(never)]
+fn overflow(len: usize) -> Vec<u8> {
+    let mut v = vec![0u8; len];
+    unsafe { v.as_mut_ptr().add(len).write(0xff) };
+    v
+}
+
+#[test]
+fn overflow_reports_allocation_stack() {
+    if std::env::var_os(CHILD).is_some() {
+        ALLOC.enable_red_zones();
+        let v = overflow(16);
+        assert_eq!(ALLOC.check_heap(), 1);
+        drop(v);
+        assert_eq!(ALLOC.corrupted_blocks(), 2);
+        return;
+    }
+    let output = Command::new(std::env::current_exe().unwrap())
+        .args([
+            "--exact",
+            "overflow_reports_allocation_stack",
+            "--nocapture",
+        ])
+        .env(CHILD, "1")
+        .output()
+        .unwrap();
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(output.status.success(), "{}", stderr);
+    assert_eq!(stderr.matches("buffer overflow").count(), 2, "{}", stderr);
+    assert_eq!(stderr.matches("allocated at:").count(), 2, "{}", stderr);
+    assert!(stderr.contains("red_zone::overflow"), "{}", stderr);
+}
@@ -0,0 +1,71 @@
+use std::alloc::System;
+use std::thread;
+
+use logging_allocator::{LoggingAllocator, MAX_REGIONS};
+
+#[global_allocator]
+static ALLOC: LoggingAllocator<System> = LoggingAllocator::with_allocator(System, false);
+
+fn live_bytes(name: &str) -> usize {
+    ALLOC.region_stats(name).unwrap().live_bytes
+}
+
+#[test]
+fn regions() {
+    // Allocations are attributed to the innermost region only.
+    let outer = ALLOC.region("outer");
+    let a = Box::new([0u8; 1000]);
+    let inner = ALLOC.region("inner");
+    let b = Box::new([0u8; 500]);
+    let inner = inner.end().unwrap();
+    assert_eq!(
+        (inner.allocations, inner.bytes, inner.live_bytes),
+        (1, 500, 500)
+    );
+    let c = Box::new([0u8; 200]);
+    let stats = outer.stats().unwrap();
+    assert_eq!(
+        (stats.allocations, stats.bytes, stats.live_bytes),
+        (2, 1200, 1200)
+    );
+    drop(outer);
+    drop(Box::new([0u8; 100]));
+    assert_eq!(ALLOC.region_stats("outer").unwrap().allocations, 2);
+
+    // Freeing a block lowers the live bytes of its region but not the peak.
+    drop(a);
+    let stats = ALLOC.region_stats("outer").unwrap();
+    assert_eq!((stats.live_bytes, stats.peak_live_bytes), (200, 1200));
+
+    // A block freed on another thread is credited to the region it was allocated in.
+    thread::spawn(move || {
+        let _other = ALLOC.region("other");
+        drop(b);
+        drop(c);
+    })
+    .join()
+    .unwrap();
+    assert_eq!(live_bytes("inner"), 0);
+    assert_eq!(live_bytes("outer"), 0);
+    assert_eq!(ALLOC.region_stats("other").unwrap().allocations, 0);
+
+    // Regions beyond the limit are not registered, and allocations in them are attributed to the
+    // enclosing region instead.
+    let outer = ALLOC.region("outer");
+    let guards: Vec<_> = (ALLOC.regions().len()..MAX_REGIONS)
+        .map(|index| ALLOC.region(Box::leak(format!("region {}", index).into_boxed_str())))
+        .collect();
+    assert!(guards.iter().all(|guard| guard.stats().is_some()));
+    let overflow = ALLOC.region("overflow");
+    assert!(overflow.stats().is_none());
+    assert!(ALLOC.region_stats("overflow").is_none());
+    let last = format!("region {}", MAX_REGIONS - 1);
+    let before = live_bytes(&last);
+    let d = Box::new([0u8; 300]);
+    assert_eq!(live_bytes(&last), before + 300);
+    drop(d);
+    drop(overflow);
+    guards.into_iter().rev().for_each(drop);
+    drop(outer);
+    assert_eq!(ALLOC.regions().len(), MAX_REGIONS);
+}
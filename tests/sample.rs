@@ -0,0 +1,56 @@
+use std::alloc::System;
+use std::sync::Mutex;
+use std::thread;
+
+use logging_allocator::{AllocEvent, LoggingAllocator, Sink};
+
+/// A sink that remembers the events logged on the current thread.
+struct Events(Mutex<Vec<AllocEvent>>);
+
+impl Sink for Events {
+    fn log(&self, event: &AllocEvent) {
+        self.0.lock().unwrap().push(*event);
+    }
+}
+
+#[global_allocator]
+static ALLOC: LoggingAllocator<System, Events> =
+    LoggingAllocator::with_sink(System, Events(Mutex::new(Vec::new())), false);
+
+/// Allocate and free a block of `size` bytes on a new thread, which starts sampling afresh, and
+/// return the events logged for it.
+fn events(size: usize) -> Vec<AllocEvent> {
+    let address = thread::spawn(move || {
+        ALLOC.enable_logging_on_current_thread();
+        let block = vec![0u8; size];
+        let address = block.as_ptr() as usize;
+        drop(block);
+        ALLOC.disable_logging_on_current_thread();
+        address
+    })
+    .join()
+    .unwrap();
+    let events = ALLOC.sink().0.lock().unwrap();
+    events
+        .iter()
+        .filter(|event| event.block().address == address && event.block().size == size)
+        .copied()
+        .collect()
+}
+
+#[test]
+fn sampled_blocks() {
+    ALLOC.enable_sampling(4096);
+    // A block this much larger than the interval is all but certain to be sampled.
+    let logged = events(1 << 20);
+    assert_eq!(logged.len(), 2);
+    assert!(matches!(logged[0], AllocEvent::AllocZeroed { .. }));
+    assert!(matches!(logged[1], AllocEvent::Dealloc { .. }));
+    assert!(logged
+        .iter()
+        .all(|event| event.info().weight == logged[0].info().weight));
+
+    // A block this much smaller is all but certain not to be, and neither is freeing it.
+    ALLOC.enable_sampling(1 << 40);
+    assert!(events(64).is_empty());
+}
@@ -0,0 +1,62 @@
+use std::alloc::System;
+use std::panic;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use logging_allocator::{run_guarded, run_suppressed, AllocEvent, LoggingAllocator, Sink};
+
+/// A sink that counts the events logged.
+struct Count(AtomicU64);
+
+impl Sink for Count {
+    fn log(&self, _: &AllocEvent) {
+        self.0.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+#[global_allocator]
+static ALLOC: LoggingAllocator<System, Count> =
+    LoggingAllocator::with_sink(System, Count(AtomicU64::new(0)), false);
+
+/// The number of events logged on this thread while running `f`. Only this thread logs.
+fn logged(f: impl FnOnce()) -> u64 {
+    ALLOC.enable_logging_on_current_thread();
+    let before = ALLOC.sink().0.load(Ordering::Relaxed);
+    f();
+    let after = ALLOC.sink().0.load(Ordering::Relaxed);
+    ALLOC.disable_logging_on_current_thread();
+    after - before
+}
+
+#[test]
+fn suppression() {
+    // Guards nest, and logging resumes once the outer one is dropped.
+    assert_eq!(
+        logged(|| {
+            let outer = ALLOC.suppress();
+            let inner = ALLOC.suppress();
+            drop(Box::new(1u64));
+            drop(inner);
+            drop(Box::new(2u64));
+            drop(outer);
+        }),
+        0
+    );
+    assert_eq!(logged(|| drop(Box::new(3u64))), 2);
+
+    // `run_suppressed` always runs the closure, while `run_guarded` skips it when nested.
+    let mut runs = 0;
+    assert_eq!(
+        logged(|| run_suppressed(|| {
+            run_suppressed(|| runs += 1);
+            run_guarded(|| runs += 10);
+            drop(Box::new(4u64));
+        })),
+        0
+    );
+    assert_eq!(runs, 1);
+    assert_eq!(run_suppressed(|| 5), 5);
+
+    // A panic drops the guard.
+    assert!(panic::catch_unwind(|| run_suppressed(|| panic!("suppressed"))).is_err());
+    assert_eq!(logged(|| drop(Box::new(6u64))), 2);
+}
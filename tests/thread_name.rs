@@ -0,0 +1,44 @@
+use std::alloc::System;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::thread;
+
+use logging_allocator::{AllocEvent, LoggingAllocator, Sink};
+
+/// A sink that counts the events logged.
+struct Count(AtomicU64);
+
+impl Sink for Count {
+    fn log(&self, _: &AllocEvent) {
+        self.0.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+#[global_allocator]
+static ALLOC: LoggingAllocator<System, Count> =
+    LoggingAllocator::with_sink(System, Count(AtomicU64::new(0)), false);
+
+fn logged_on(name: &str) -> u64 {
+    let logged = || {
+        let before = ALLOC.sink().0.load(Ordering::Relaxed);
+        drop(Box::new(1u64));
+        ALLOC.sink().0.load(Ordering::Relaxed) - before
+    };
+    let thread = thread::Builder::new().name(name.to_owned());
+    thread.spawn(logged).unwrap().join().unwrap()
+}
+
+#[test]
+fn replace_filter_while_logging() {
+    ALLOC.enable_logging();
+    // Clearing the filter frees the pattern before this thread has looked up whether it matches,
+    // so deciding whether to log the free reads the pattern.
+    ALLOC.set_thread_name_filter("worker-*");
+    ALLOC.clear_thread_name_filter();
+    ALLOC.set_thread_name_filter("worker-*");
+    assert_eq!(logged_on("worker-1"), 2);
+    assert_eq!(logged_on("main-1"), 0);
+    ALLOC.set_thread_name_filter("main-*");
+    assert_eq!(logged_on("worker-1"), 0);
+    ALLOC.clear_thread_name_filter();
+    ALLOC.disable_logging();
+}
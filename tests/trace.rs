@@ -0,0 +1,59 @@
+use std::alloc::System;
+use std::io::{self, Cursor};
+use std::process::Command;
+
+use logging_allocator::{AllocEvent, LoggingAllocator, TraceReader, TraceWriter};
+
+static TRACE: TraceWriter<Vec<u8>> = TraceWriter::new();
+
+#[global_allocator]
+static ALLOC: LoggingAllocator<System, &TraceWriter<Vec<u8>>> =
+    LoggingAllocator::with_sink(System, &TRACE, false);
+
+/// Set when the test binary is run again to check the exit flush in a separate process.
+const CHILD: &str = "LOGGING_ALLOCATOR_TRACE_CHILD";
+
+#[test]
+fn growing_writer() {
+    if std::env::var_os(CHILD).is_some() {
+        return;
+    }
+    // The writer allocates as it grows, while the sink is the global allocator's.
+    TRACE.attach(Vec::new()).unwrap();
+    ALLOC.enable_logging_on_current_thread();
+    let block = Box::new([0u8; 100]);
+    let address = &*block as *const _ as usize;
+    drop(block);
+    TRACE.flush().unwrap();
+    let trace = TRACE.detach().unwrap().unwrap();
+    ALLOC.disable_logging_on_current_thread();
+
+    let events = TraceReader::new(Cursor::new(trace))
+        .unwrap()
+        .collect::<io::Result<Vec<_>>>()
+        .unwrap();
+    assert!(events.iter().any(|event| matches!(
+        event.event,
+        AllocEvent::Dealloc { block, .. } if block.address == address && block.size == 100
+    )));
+}
+
+static EXIT_TRACE: TraceWriter<io::Stderr> = TraceWriter::new();
+
+#[test]
+fn flush_at_exit() {
+    if std::env::var_os(CHILD).is_some() {
+        EXIT_TRACE.attach(io::stderr()).unwrap();
+        EXIT_TRACE.flush_at_exit();
+        return;
+    }
+    let output = Command::new(std::env::current_exe().unwrap())
+        .args(["--exact", "flush_at_exit", "--nocapture"])
+        .env(CHILD, "1")
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    let mut reader = TraceReader::new(Cursor::new(output.stderr)).unwrap();
+    assert!(reader.next().is_none());
+    assert!(!reader.modules().is_empty());
+}